@@ -1,5 +1,11 @@
 #!/bin/sh
 
+# The health check uses the built-in HTTP server, which is disabled by default, so the config must
+# enable it on a loopback address, e.g.
+#
+#   [http]
+#   listen = "127.0.0.1:9353"
+
 if podman container exists cfdns; then
     podman start cfdns
 else
@@ -7,6 +13,8 @@ else
         --net=host \
         --name cfdns \
         --security-opt=no-new-privileges \
+        --health-cmd="wget -q -O /dev/null http://127.0.0.1:9353/healthz || exit 1" \
+        --health-interval=60s \
         -e CONFIG=/etc/cfdns.toml \
         -v /mnt/data/cfdns/config.toml:/etc/cfdns.toml \
         bitwalker/cfdns:latest
@@ -0,0 +1,117 @@
+//! This module implements the audit log, an append-only record of every change made in Cloudflare.
+//!
+//! The log is stored in `audit.jsonl` in the data directory, with one JSON object per line. Both
+//! successful and failed changes are recorded, so that the log can be used to reconstruct when an
+//! address changed, and to diagnose why a change didn't take effect.
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cloudflare::{DnsContent, DnsRecordType, Id};
+use crate::time::Timestamp;
+
+/// This enum represents the kinds of changes which are recorded in the audit log
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A single entry in the audit log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: Timestamp,
+    pub action: Action,
+    /// The interface whose address triggered the change
+    pub interface: String,
+    pub zone: String,
+    pub record: String,
+    #[serde(rename = "type")]
+    pub ty: DnsRecordType,
+    /// The Cloudflare identifier of the record, if known
+    pub id: Option<Id>,
+    pub old: Option<DnsContent>,
+    /// The content of the record after the change, which is unset if it was deleted
+    pub new: Option<DnsContent>,
+    /// The HTTP status of Cloudflare's response, if one was received
+    pub status: Option<u16>,
+    /// The reason the change failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl AuditEntry {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Returns the path at which the audit log is stored
+pub fn path() -> anyhow::Result<PathBuf> {
+    // Tests which make changes mustn't write to the real audit log
+    if cfg!(test) {
+        let name = format!("cfdns-audit-{}.jsonl", std::process::id());
+        return Ok(std::env::temp_dir().join(name));
+    }
+    Ok(crate::config::file::data_dir()?.join("audit.jsonl"))
+}
+
+/// Appends `entry` to the audit log
+///
+/// Failing to write to the audit log is not considered fatal, since the change it describes has
+/// already happened, so errors are logged rather than returned.
+pub fn record(entry: &AuditEntry) {
+    if let Err(e) = path().and_then(|path| append(&path, entry)) {
+        warn!("Unable to write to audit log: {:#}", e);
+    }
+}
+
+fn append(path: &Path, entry: &AuditEntry) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // Each entry is written with a single call, so concurrent writers never interleave entries
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to append to {}", path.display()))
+}
+
+/// Reads all entries from the audit log at `path`, oldest first
+///
+/// Lines which cannot be parsed (e.g. due to a partial write) are skipped with a warning.
+pub fn read(path: &Path) -> anyhow::Result<Vec<AuditEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open audit log at {}", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!(
+                "Skipping invalid entry on line {} of {}: {}",
+                index + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(entries)
+}
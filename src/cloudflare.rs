@@ -1,15 +1,21 @@
 use std::fmt::{self, Display};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail};
-use serde::de::Visitor;
+use reqwest::blocking::RequestBuilder;
+use serde::de::{DeserializeOwned, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 
+use crate::metrics;
+use crate::secret::Secret;
 use crate::system::AddressFamily;
+use crate::time::Timestamp;
 
 /// This type represents a non-empty identifier string bound to a Cloudflare resource
-#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
 #[repr(transparent)]
 pub struct Id(String);
 impl Display for Id {
@@ -38,6 +44,9 @@ impl<'de> Deserialize<'de> for Id {
 pub struct Zone {
     pub id: Id,
     pub name: String,
+    /// The permissions the credentials used to fetch this zone have for it, if Cloudflare reported them
+    #[serde(default)]
+    permissions: Option<Vec<String>>,
     #[serde(skip)]
     pub records: Vec<DnsRecord>,
 }
@@ -46,12 +55,21 @@ impl Zone {
         Self {
             id,
             name,
+            permissions: None,
             records: vec![],
         }
     }
 
-    pub fn get(name: &str, token: &str) -> anyhow::Result<Self> {
-        let client = Cloudflare::new(token.to_string())?;
+    /// Returns whether the credentials used to fetch this zone can create and update its DNS records
+    ///
+    /// This is based on the permissions Cloudflare reported for the zone, so is `None` if it didn't.
+    pub fn can_edit_records(&self) -> Option<bool> {
+        self.permissions
+            .as_ref()
+            .map(|permissions| permissions.iter().any(|p| p == "#dns_records:edit"))
+    }
+
+    pub fn get(name: &str, client: &Cloudflare) -> anyhow::Result<Self> {
         if let Some(zone) = client.zone_by_name(name)? {
             Ok(zone)
         } else {
@@ -60,12 +78,16 @@ impl Zone {
     }
 }
 /// This enum represents the type of DNS records we support updating
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(
+    Default, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum DnsRecordType {
+    #[default]
     A,
     AAAA,
     CNAME,
+    TXT,
     Other,
 }
 impl Display for DnsRecordType {
@@ -73,11 +95,6 @@ impl Display for DnsRecordType {
         write!(f, "{:?}", self)
     }
 }
-impl Default for DnsRecordType {
-    fn default() -> Self {
-        Self::A
-    }
-}
 impl From<AddressFamily> for DnsRecordType {
     fn from(ty: AddressFamily) -> Self {
         match ty {
@@ -105,7 +122,7 @@ impl TryInto<AddressFamily> for DnsRecordType {
 /// have a specific TTL on hand.
 ///
 /// This struct has the same representation as a u32 value
-#[derive(Default, Copy, Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Default, Copy, Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Ttl(Option<core::num::NonZeroU32>);
 impl Display for Ttl {
@@ -135,7 +152,7 @@ impl Serialize for Ttl {
 ///
 /// We use a dedicated type here vs a boolean because it has default behavior
 /// that we wish to encode when (de)serializing
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ProxyMode {
     Proxied,
     None,
@@ -229,7 +246,8 @@ impl<'de> Deserialize<'de> for ProxyMode {
 /// This struct represents the key details of a single DNS record in Cloudflare
 ///
 /// This record is used for rendering data received from Cloudflare, as well as
-/// encoding the parameters for create/update operations.
+/// encoding the parameters for create operations. Updates only send the fields
+/// which cfdns manages, see `RecordPatch`.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DnsRecord {
     #[serde(skip_serializing)]
@@ -244,43 +262,77 @@ pub struct DnsRecord {
     pub proxied: ProxyMode,
     #[serde(default)]
     pub ttl: Ttl,
-}
-impl DnsRecord {
-    /// Given an IPv4 or IPv6 address, attempts to update this DNS record.
+    /// The comment on the record
     ///
-    /// If the record is of a matching address type, and a change was applied,
-    /// then `Ok(true)` is returned. If no change was made, then `Ok(false)` is
-    /// returned.
+    /// For records built from the config, `None` means the comment isn't managed by cfdns.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The tags on the record, each of the form `name:value`
     ///
-    /// If the record is of a different address type, then `Err(())` is returned.
-    pub fn try_update(&mut self, addr: IpAddr) -> anyhow::Result<bool> {
-        match addr {
-            IpAddr::V4(desired) if self.ty == DnsRecordType::A => match self.content {
-                DnsContent::A(current) if current == desired => Ok(false),
-                DnsContent::A(_) => {
-                    self.content = DnsContent::A(desired);
-                    Ok(true)
-                }
-                _ => bail!(
-                    "unable to update record for {} with address of different type",
-                    &self.name
-                ),
-            },
-            IpAddr::V6(desired) if self.ty == DnsRecordType::AAAA => match self.content {
-                DnsContent::AAAA(current) if current == desired => Ok(false),
-                DnsContent::AAAA(_) => {
-                    self.content = DnsContent::AAAA(desired);
-                    Ok(true)
-                }
-                _ => bail!(
-                    "unable to update record for {} with address of different type",
-                    &self.name
-                ),
-            },
-            _ => bail!(
-                "unable to update record for {} with address of different type",
-                &self.name
-            ),
+    /// For records built from the config, `None` means the tags aren't managed by cfdns.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    /// The fields of the record which cfdns doesn't manage, e.g. its settings
+    ///
+    /// These are kept exactly as received from Cloudflare. They are included when creating a
+    /// record, but never when updating one, so that changes made elsewhere aren't overwritten.
+    #[serde(flatten)]
+    pub unmanaged: Map<String, Value>,
+}
+
+/// The changes submitted to the batch endpoint, which are applied atomically
+#[derive(Serialize)]
+struct Batch<'a> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deletes: Vec<BatchDelete<'a>>,
+    posts: Vec<&'a DnsRecord>,
+    patches: Vec<BatchPatch<'a>>,
+}
+
+/// The deletion of a single record, as part of a batch
+#[derive(Serialize)]
+struct BatchDelete<'a> {
+    id: &'a Id,
+}
+
+/// An update to a single record, as part of a batch
+#[derive(Serialize)]
+struct BatchPatch<'a> {
+    id: &'a Id,
+    #[serde(flatten)]
+    patch: RecordPatch<'a>,
+}
+
+/// The records created and updated by a batch
+#[derive(Deserialize)]
+struct BatchResult {
+    #[serde(default)]
+    posts: Vec<DnsRecord>,
+    #[serde(default)]
+    patches: Vec<DnsRecord>,
+}
+
+/// The fields of a DNS record which cfdns manages, sent when updating an existing record
+///
+/// Updates are partial, so any other fields set on the record, e.g. in the dashboard, are untouched.
+#[derive(Serialize)]
+struct RecordPatch<'a> {
+    content: &'a DnsContent,
+    proxied: ProxyMode,
+    ttl: Ttl,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<&'a [String]>,
+}
+impl<'a> From<&'a DnsRecord> for RecordPatch<'a> {
+    fn from(record: &'a DnsRecord) -> Self {
+        Self {
+            content: &record.content,
+            proxied: record.proxied,
+            ttl: record.ttl,
+            comment: record.comment.as_deref(),
+            tags: record.tags.as_deref(),
         }
     }
 }
@@ -356,159 +408,666 @@ impl FromStr for DnsContent {
     }
 }
 
+/// The base URL of the Cloudflare API
+const API_BASE: &str = "https://api.cloudflare.com/client/v4";
+
+/// The number of results requested per page, when listing DNS records
+const PAGE_SIZE: u32 = 1000;
+
 /// This struct represents the payload returned from the Cloudflare API
 #[derive(Serialize, Deserialize)]
 struct Response<T> {
     success: bool,
     result: Option<T>,
-    errors: Vec<ResponseError>,
+    /// Describes the page of results returned, for requests which list resources
+    #[serde(default)]
+    result_info: Option<ResultInfo>,
+    #[serde(default)]
+    errors: Vec<ApiMessage>,
+    #[serde(default)]
+    messages: Vec<ApiMessage>,
+    /// The HTTP status of the response
+    #[serde(skip)]
+    status: u16,
 }
 impl<T> Response<T> {
-    /// Converts the Response object to a Result based on whether it was successful or not, unwrapping the payload
-    fn ok(mut self) -> anyhow::Result<T> {
-        if self.success {
-            Ok(self.result.ok_or_else(|| {
-                anyhow!(
-                    "expected successful response to contain payload of type {}, but got null",
-                    std::any::type_name::<T>()
-                )
-            })?)
-        } else {
-            Err(self.errors.pop().unwrap().into())
+    /// Unwraps the payload of a successful response
+    fn ok(self) -> anyhow::Result<T> {
+        self.result.ok_or_else(|| {
+            anyhow!(
+                "expected successful response to contain payload of type {}, but got null",
+                std::any::type_name::<T>()
+            )
+        })
+    }
+}
+
+/// This struct describes a single page of results in a Cloudflare API response
+#[derive(Serialize, Deserialize)]
+struct ResultInfo {
+    #[serde(default)]
+    total_pages: u32,
+}
+
+/// This struct represents an error or informational message included in a Cloudflare API response
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiMessage {
+    pub code: u32,
+    pub message: String,
+}
+impl Display for ApiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", &self.message, self.code)
+    }
+}
+
+/// This struct represents the details of a request which Cloudflare responded to with an error
+#[derive(Clone, Debug)]
+pub struct Failure {
+    /// The HTTP status of the response
+    pub status: u16,
+    pub errors: Vec<ApiMessage>,
+    pub messages: Vec<ApiMessage>,
+}
+impl Failure {
+    /// Returns true if any of the errors in the response have one of the given codes
+    fn has_code(&self, codes: &[u32]) -> bool {
+        self.errors.iter().any(|e| codes.contains(&e.code))
+    }
+}
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP {}", self.status)?;
+        for (i, message) in self.errors.iter().chain(self.messages.iter()).enumerate() {
+            let sep = if i == 0 { ": " } else { "; " };
+            write!(f, "{}{}", sep, message)?;
         }
+        Ok(())
+    }
+}
+
+/// Error codes returned by Cloudflare when the credentials are missing, invalid, or lack permission
+const AUTH_CODES: &[u32] = &[6003, 9103, 9106, 9107, 9109, 10000];
+/// Error codes returned by Cloudflare when a zone or record doesn't exist
+const NOT_FOUND_CODES: &[u32] = &[7003, 81044];
+/// Error codes returned by Cloudflare when a record conflicts with one which already exists
+const CONFLICT_CODES: &[u32] = &[81053, 81054, 81055, 81057, 81058];
+/// Error codes returned by Cloudflare when too many requests have been made
+const RATE_LIMIT_CODES: &[u32] = &[971, 10429];
+
+/// This enum represents the ways in which a request to the Cloudflare API can fail
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Cloudflare rejected the credentials, check them with `cfdns token verify` ({0})")]
+    Auth(Failure),
+    #[error("Not found in Cloudflare ({0})")]
+    NotFound(Failure),
+    #[error("Rate limited by Cloudflare{} ({failure})", retry_after.map(|d| format!(", retry after {}s", d.as_secs())).unwrap_or_default())]
+    RateLimited {
+        retry_after: Option<Duration>,
+        failure: Failure,
+    },
+    #[error("Cloudflare rejected the request as invalid ({failure})")]
+    Validation { code: Option<u32>, failure: Failure },
+    #[error("Conflicts with an existing record, e.g. a CNAME with the same name ({0})")]
+    Conflict(Failure),
+    #[error("Unable to reach Cloudflare")]
+    Transport(#[from] reqwest::Error),
+    #[error("Unable to understand the response from Cloudflare (HTTP {status})")]
+    Decode {
+        status: u16,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Cloudflare request failed ({0})")]
+    Api(Failure),
+}
+impl Error {
+    /// Determines the kind of error described by `failure`
+    fn classify(failure: Failure, retry_after: Option<Duration>) -> Self {
+        match failure.status {
+            _ if failure.has_code(CONFLICT_CODES) => Self::Conflict(failure),
+            401 | 403 => Self::Auth(failure),
+            _ if failure.has_code(AUTH_CODES) => Self::Auth(failure),
+            404 => Self::NotFound(failure),
+            _ if failure.has_code(NOT_FOUND_CODES) => Self::NotFound(failure),
+            429 => Self::RateLimited {
+                retry_after,
+                failure,
+            },
+            _ if failure.has_code(RATE_LIMIT_CODES) => Self::RateLimited {
+                retry_after,
+                failure,
+            },
+            400..=499 => Self::Validation {
+                code: failure.errors.first().map(|e| e.code),
+                failure,
+            },
+            _ => Self::Api(failure),
+        }
+    }
+
+    /// Returns the HTTP status of the response, if one was received
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Auth(failure)
+            | Self::NotFound(failure)
+            | Self::RateLimited { failure, .. }
+            | Self::Validation { failure, .. }
+            | Self::Conflict(failure)
+            | Self::Api(failure) => Some(failure.status),
+            Self::Transport(e) => e.status().map(|status| status.as_u16()),
+            Self::Decode { status, .. } => Some(*status),
+        }
+    }
+}
+
+/// Returns the Cloudflare API error which caused `error`, if there was one
+pub fn api_error(error: &anyhow::Error) -> Option<&Error> {
+    error.chain().find_map(|e| e.downcast_ref::<Error>())
+}
+
+/// Returns the HTTP status of the response which caused `error`, if there was one
+pub fn error_status(error: &anyhow::Error) -> Option<u16> {
+    api_error(error).and_then(|e| e.status())
+}
+
+/// Converts the result of a request into whether or not it was permitted
+///
+/// Requests rejected due to the credentials were not permitted, other failures are returned.
+fn permitted<T>(result: Result<T, Error>) -> anyhow::Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(Error::Auth(_)) => Ok(false),
+        Err(e) => Err(e.into()),
     }
 }
 
-#[derive(thiserror::Error, Debug, Serialize, Deserialize)]
-#[error("Request failed with code {code}: {message}")]
-struct ResponseError {
-    code: usize,
-    message: String,
+/// This struct represents the status of an API token, as reported by Cloudflare
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenStatus {
+    /// One of `active`, `disabled` or `expired`
+    pub status: String,
+    #[serde(default)]
+    pub expires_on: Option<Timestamp>,
+    #[serde(default)]
+    pub not_before: Option<Timestamp>,
 }
 
-/// This struct represents an instantiation of a Cloudflare API client, bound to a specific token
+/// This enum represents the credentials used to authenticate with the Cloudflare API
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Credentials {
+    /// A scoped API token
+    Token(Secret),
+    /// The legacy Global API Key, along with the email address of the account it belongs to
+    GlobalApiKey { email: String, key: Secret },
+}
+impl Credentials {
+    /// Describes the kind of credentials, for display
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Token(_) => "api_token",
+            Self::GlobalApiKey { .. } => "global_api_key",
+        }
+    }
+}
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::Token(Secret::default())
+    }
+}
+
+/// This struct represents an instantiation of a Cloudflare API client, bound to specific credentials
+///
+/// Cloning a client is cheap, and clones share the same underlying connection pool.
+#[derive(Clone)]
 pub struct Cloudflare {
     client: reqwest::blocking::Client,
+    /// The base URL of the API, which is only changed to point tests at a stand-in
+    base: String,
 }
 impl Cloudflare {
     /// Create a new Cloudflare API client
-    pub fn new(token: String) -> anyhow::Result<Self> {
+    pub fn new(credentials: &Credentials) -> anyhow::Result<Self> {
         use reqwest::header::{self, HeaderMap, HeaderValue};
-        use std::time::Duration;
 
         let mut headers = HeaderMap::new();
-        let bearer = format!("Bearer {}", &token);
-        let mut key = HeaderValue::from_str(&bearer)?;
-        key.set_sensitive(true);
-        headers.insert(header::AUTHORIZATION, key);
+        match credentials {
+            Credentials::Token(token) => {
+                let bearer = format!("Bearer {}", token.expose());
+                let mut key = HeaderValue::from_str(&bearer)?;
+                key.set_sensitive(true);
+                headers.insert(header::AUTHORIZATION, key);
+            }
+            Credentials::GlobalApiKey { email, key } => {
+                headers.insert("X-Auth-Email", HeaderValue::from_str(email)?);
+                let mut key = HeaderValue::from_str(key.expose())?;
+                key.set_sensitive(true);
+                headers.insert("X-Auth-Key", key);
+            }
+        }
 
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(30))
             .default_headers(headers)
             .build()?;
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            base: API_BASE.to_string(),
+        })
     }
 
-    /// Fetch the zone identifier for the zone with the given domain name
-    pub fn zone_by_name(&self, name: &str) -> anyhow::Result<Option<Zone>> {
-        let response: Response<Vec<Zone>> = self
-            .client
-            .get("https://api.cloudflare.com/client/v4/zones".to_string())
-            .query(&[("name", name), ("status", "active")])
-            .send()?
-            .error_for_status()?
-            .json()?;
+    /// Returns a copy of this client which sends requests to `base` rather than Cloudflare
+    #[cfg(test)]
+    pub fn with_base(&self, base: &str) -> Self {
+        Self {
+            client: self.client.clone(),
+            base: base.to_string(),
+        }
+    }
 
-        let mut zones = response.ok()?;
+    /// Sends a request to the Cloudflare API, and decodes the response
+    ///
+    /// Responses which indicate a failure are converted into the appropriate kind of `Error`. The
+    /// outcome and latency of the request are recorded in the metrics registry under `endpoint`.
+    fn send<T>(&self, endpoint: &'static str, request: RequestBuilder) -> Result<Response<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let start = Instant::now();
+        let response = match request.send() {
+            Ok(response) => response,
+            Err(e) => {
+                metrics::request(endpoint, None, start.elapsed());
+                return Err(e.into());
+            }
+        };
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let result = response.json::<Response<T>>();
+        metrics::request(endpoint, Some(status.as_u16()), start.elapsed());
 
-        Ok(zones.pop())
+        match result {
+            Ok(mut response) if response.success && status.is_success() => {
+                response.status = status.as_u16();
+                Ok(response)
+            }
+            Ok(response) => Err(Error::classify(
+                Failure {
+                    status: status.as_u16(),
+                    errors: response.errors,
+                    messages: response.messages,
+                },
+                retry_after,
+            )),
+            // A successful response we can't decode is a problem on our end, not Cloudflare's
+            Err(source) if status.is_success() => Err(Error::Decode {
+                status: status.as_u16(),
+                source,
+            }),
+            // Error responses don't always have a body, e.g. when rejected by a proxy
+            Err(_) => Err(Error::classify(
+                Failure {
+                    status: status.as_u16(),
+                    errors: Vec::new(),
+                    messages: Vec::new(),
+                },
+                retry_after,
+            )),
+        }
+    }
+
+    /// Verifies the token this client was created with, returning its status
+    pub fn verify_token(&self) -> anyhow::Result<TokenStatus> {
+        let request = self.client.get(format!("{}/user/tokens/verify", self.base));
+        let response: Response<TokenStatus> = self.send("tokens.verify", request)?;
+
+        response.ok()
+    }
+
+    /// Verifies the Global API Key this client was created with, by fetching the account it belongs to
+    pub fn verify_key(&self) -> anyhow::Result<()> {
+        let request = self.client.get(format!("{}/user", self.base));
+        let response: Response<serde_json::Value> = self.send("user.get", request)?;
+
+        response.ok().map(|_| ())
+    }
+
+    /// Fetch the details of the zone with the given identifier
+    pub fn zone_by_id(&self, id: &Id) -> anyhow::Result<Zone> {
+        let request = self.client.get(format!("{}/zones/{}", self.base, id));
+        let response: Response<Zone> = self.send("zones.get", request)?;
+
+        response.ok()
     }
 
-    /// Get the current DNS record binding for the given name, in the given zone
-    pub fn get_by_name(&self, zone_id: &Id, name: &str) -> anyhow::Result<Option<DnsRecord>> {
-        let response: Response<Vec<DnsRecord>> = self
+    /// Checks whether DNS records in the given zone can be listed
+    pub fn can_read_records(&self, zone_id: &Id) -> anyhow::Result<bool> {
+        let request = self
             .client
-            .get(format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-                zone_id
-            ))
-            .query(&[("name", name)])
-            .send()?
-            .error_for_status()?
-            .json()?;
+            .get(format!("{}/zones/{}/dns_records", self.base, zone_id))
+            .query(&[("per_page", "5")]);
+        permitted(self.send::<Vec<DnsRecord>>("dns_records.list", request))
+    }
+
+    /// Fetch the zone identifier for the zone with the given domain name
+    pub fn zone_by_name(&self, name: &str) -> anyhow::Result<Option<Zone>> {
+        let request = self
+            .client
+            .get(format!("{}/zones", self.base))
+            .query(&[("name", name), ("status", "active")]);
+        let response: Response<Vec<Zone>> = self.send("zones.list", request)?;
 
-        let mut records = response.ok()?;
+        let mut zones = response.ok()?;
 
-        Ok(records.pop())
+        Ok(zones.pop())
     }
 
-    /// Get the current DNS record binding for the given name and type, in the given zone
-    pub fn get(
+    /// List all DNS records in the given zone, optionally only those with the given name and/or type
+    ///
+    /// Results are paginated by Cloudflare, so this makes as many requests as needed to get all of them.
+    pub fn list(
         &self,
         zone_id: &Id,
-        name: &str,
-        ty: DnsRecordType,
-    ) -> anyhow::Result<Option<DnsRecord>> {
-        let ty = ty.to_string();
-        let response: Response<Vec<DnsRecord>> = self
-            .client
-            .get(format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-                zone_id
-            ))
-            .query(&[("name", name), ("type", ty.as_str())])
-            .send()?
-            .error_for_status()?
-            .json()?;
+        name: Option<&str>,
+        ty: Option<DnsRecordType>,
+    ) -> anyhow::Result<Vec<DnsRecord>> {
+        let mut filters = Vec::new();
+        if let Some(name) = name {
+            filters.push(("name", name.to_string()));
+        }
+        if let Some(ty) = ty {
+            filters.push(("type", ty.to_string()));
+        }
 
-        let mut records = response.ok()?;
+        let mut records = Vec::new();
+        let mut page = 1;
+        loop {
+            let request = self
+                .client
+                .get(format!("{}/zones/{}/dns_records", self.base, zone_id))
+                .query(&filters)
+                .query(&[("page", page), ("per_page", PAGE_SIZE)]);
+            let response: Response<Vec<DnsRecord>> = self.send("dns_records.list", request)?;
+            let pages = response
+                .result_info
+                .as_ref()
+                .map_or(1, |info| info.total_pages);
+            records.extend(response.ok()?);
+            if page >= pages {
+                break;
+            }
+            page += 1;
+        }
 
-        Ok(records.pop())
+        Ok(records)
     }
 
     /// Create the given DNS record
-    pub fn create(&self, record: &mut DnsRecord) -> anyhow::Result<()> {
+    ///
+    /// Returns the HTTP status of Cloudflare's response.
+    pub fn create(&self, record: &mut DnsRecord) -> anyhow::Result<u16> {
         if record.id.is_some() {
             bail!("Cannot create a DNS record with a resource id set");
         }
         let zone_id = &record.zone_id;
-        let response: Response<DnsRecord> = self
+        let request = self
             .client
-            .post(format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-                zone_id
-            ))
-            .json(&record)
-            .send()?
-            .error_for_status()?
-            .json()?;
+            .post(format!("{}/zones/{}/dns_records", self.base, zone_id))
+            .json(&record);
+        let response: Response<DnsRecord> = self.send("dns_records.create", request)?;
+        let status = response.status;
 
         *record = response.ok()?;
 
-        Ok(())
+        Ok(status)
     }
 
     /// Update the given DNS record
-    pub fn update(&self, record: &mut DnsRecord) -> anyhow::Result<()> {
+    ///
+    /// Only the content, proxy mode and TTL of the record, and its comment and tags if set, are
+    /// changed, any other fields are left as they are in Cloudflare. On success, `record` is
+    /// replaced with Cloudflare's copy.
+    ///
+    /// Returns the HTTP status of Cloudflare's response.
+    pub fn update(&self, record: &mut DnsRecord) -> anyhow::Result<u16> {
         if let Some(id) = &record.id {
             let zone_id = &record.zone_id;
-            let response: Response<DnsRecord> = self
+            let request = self
                 .client
-                .put(format!(
-                    "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-                    zone_id, id
+                .patch(format!(
+                    "{}/zones/{}/dns_records/{}",
+                    self.base, zone_id, id
                 ))
-                .json(&record)
-                .send()?
-                .error_for_status()?
-                .json()?;
+                .json(&RecordPatch::from(&*record));
+            let response: Response<DnsRecord> = self.send("dns_records.update", request)?;
+            let status = response.status;
 
             *record = response.ok()?;
 
-            Ok(())
+            Ok(status)
         } else {
             bail!("Cannot update a DNS record that is missing its Cloudflare resource id");
         }
     }
+
+    /// Delete the given DNS record
+    ///
+    /// Returns the HTTP status of Cloudflare's response.
+    pub fn delete(&self, record: &DnsRecord) -> anyhow::Result<u16> {
+        if let Some(id) = &record.id {
+            let request = self.client.delete(format!(
+                "{}/zones/{}/dns_records/{}",
+                self.base, &record.zone_id, id
+            ));
+            let response: Response<serde_json::Value> = self.send("dns_records.delete", request)?;
+
+            Ok(response.status)
+        } else {
+            bail!("Cannot delete a DNS record that is missing its Cloudflare resource id");
+        }
+    }
+
+    /// Delete, create and update the given DNS records in a single, atomic, operation
+    ///
+    /// Records in `records` without a resource id are created, the rest are updated like `update`
+    /// does. Either all of the changes are made, or none of them are. On success, each record in
+    /// `records` is replaced with Cloudflare's copy.
+    ///
+    /// Returns the HTTP status of Cloudflare's response.
+    pub fn batch(
+        &self,
+        zone_id: &Id,
+        deletes: &[&DnsRecord],
+        records: &mut [&mut DnsRecord],
+    ) -> anyhow::Result<u16> {
+        let mut ids = Vec::new();
+        for record in deletes.iter() {
+            match record.id.as_ref() {
+                Some(id) => ids.push(BatchDelete { id }),
+                None => {
+                    bail!("Cannot delete a DNS record that is missing its Cloudflare resource id")
+                }
+            }
+        }
+        let batch = Batch {
+            deletes: ids,
+            posts: records
+                .iter()
+                .filter(|record| record.id.is_none())
+                .map(|record| &**record)
+                .collect(),
+            patches: records
+                .iter()
+                .filter_map(|record| {
+                    let id = record.id.as_ref()?;
+                    Some(BatchPatch {
+                        id,
+                        patch: RecordPatch::from(&**record),
+                    })
+                })
+                .collect(),
+        };
+        let request = self
+            .client
+            .post(format!("{}/zones/{}/dns_records/batch", self.base, zone_id))
+            .json(&batch);
+        let response: Response<BatchResult> = self.send("dns_records.batch", request)?;
+        let status = response.status;
+
+        // Results are returned in the same order as the changes were submitted
+        let result = response.ok()?;
+        let mut posts = result.posts.into_iter();
+        let mut patches = result.patches.into_iter();
+        for record in records.iter_mut() {
+            let changed = match record.id {
+                None => posts.next(),
+                Some(_) => patches.next(),
+            };
+            **record = changed.ok_or_else(|| {
+                anyhow!(
+                    "Cloudflare's response to the batch is missing {}",
+                    &record.name
+                )
+            })?;
+        }
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A request received by a stand-in for the Cloudflare API
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: String,
+        /// The path of the request, relative to the base of the API, without the query
+        pub path: String,
+        pub body: Value,
+    }
+
+    /// Starts a stand-in for the Cloudflare API on a local port, which answers each request with
+    /// the status and body returned by `respond`
+    ///
+    /// Returns a client which sends its requests to the stand-in, and the requests it received.
+    pub fn stand_in<F>(respond: F) -> (Cloudflare, Receiver<Request>)
+    where
+        F: Fn(&Request) -> (u16, Value) + Send + 'static,
+    {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/client/v4", server.server_addr());
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let url = request.url().split('?').next().unwrap_or_default();
+                let received = Request {
+                    method: request.method().to_string(),
+                    path: url.trim_start_matches("/client/v4").to_string(),
+                    body: serde_json::from_str(&body).unwrap_or(Value::Null),
+                };
+                let (status, body) = respond(&received);
+                // Hand the request over before responding, so it's available once the client returns
+                let _ = sender.send(received);
+                let response =
+                    tiny_http::Response::from_string(body.to_string()).with_status_code(status);
+                let _ = request.respond(response);
+            }
+        });
+        let client = Cloudflare::new(&Credentials::default()).unwrap();
+        (client.with_base(&base), received)
+    }
+
+    /// Returns a successful response with the given result
+    pub fn success(result: Value) -> (u16, Value) {
+        let body = json!({ "success": true, "errors": [], "messages": [], "result": result });
+        (200, body)
+    }
+
+    /// Returns a failed response with the given status and error code
+    pub fn failure(status: u16, code: u32) -> (u16, Value) {
+        let body = json!({
+            "success": false,
+            "errors": [{ "code": code, "message": "failed" }],
+            "messages": [],
+            "result": null,
+        });
+        (status, body)
+    }
+
+    fn classify(status: u16, codes: &[u32]) -> Error {
+        let failure = Failure {
+            status,
+            errors: codes
+                .iter()
+                .map(|&code| ApiMessage {
+                    code,
+                    message: "failed".to_string(),
+                })
+                .collect(),
+            messages: Vec::new(),
+        };
+        Error::classify(failure, Some(Duration::from_secs(30)))
+    }
+
+    #[test]
+    fn classifies_by_status() {
+        assert!(matches!(classify(401, &[]), Error::Auth(_)));
+        assert!(matches!(classify(403, &[]), Error::Auth(_)));
+        assert!(matches!(classify(404, &[]), Error::NotFound(_)));
+        assert!(matches!(
+            classify(429, &[]),
+            Error::RateLimited {
+                retry_after: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            classify(400, &[1004]),
+            Error::Validation {
+                code: Some(1004),
+                ..
+            }
+        ));
+        assert!(matches!(
+            classify(422, &[]),
+            Error::Validation { code: None, .. }
+        ));
+        assert!(matches!(classify(500, &[]), Error::Api(_)));
+        assert!(matches!(classify(503, &[]), Error::Api(_)));
+    }
+
+    #[test]
+    fn classifies_by_code() {
+        // Cloudflare reports some failures with a generic status, so the error code takes precedence
+        assert!(matches!(classify(400, &[9109]), Error::Auth(_)));
+        assert!(matches!(classify(400, &[7003]), Error::NotFound(_)));
+        assert!(matches!(classify(400, &[81044]), Error::NotFound(_)));
+        assert!(matches!(classify(400, &[10429]), Error::RateLimited { .. }));
+        assert!(matches!(classify(500, &[971]), Error::RateLimited { .. }));
+        assert!(matches!(classify(400, &[81053]), Error::Conflict(_)));
+        // A conflict is reported as such, even if its status suggests otherwise
+        assert!(matches!(classify(403, &[81057]), Error::Conflict(_)));
+    }
+
+    #[test]
+    fn reports_the_status() {
+        assert_eq!(classify(404, &[]).status(), Some(404));
+        assert_eq!(classify(400, &[81053]).status(), Some(400));
+    }
 }
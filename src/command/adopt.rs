@@ -0,0 +1,79 @@
+use anyhow::{anyhow, bail};
+use clap::Args;
+
+use super::{should_watch, Command};
+use crate::config::Config;
+use crate::registry::{NotOwned, Ownership, Registry};
+
+#[derive(Args)]
+pub struct Adopt {
+    /// Only adopt records bound to a specific interface
+    #[clap(short, long)]
+    pub(super) interface: Option<String>,
+    /// Only adopt records with the given name
+    #[clap(short, long)]
+    record: Option<String>,
+    /// Only adopt records with the given tag, either `name:value`, or just its name
+    #[clap(short, long)]
+    tag: Option<String>,
+    /// Also take over records owned by other instances of cfdns
+    #[clap(long)]
+    force: bool,
+}
+
+impl Command for Adopt {
+    fn invoke(&self, config: &mut Config) -> anyhow::Result<()> {
+        let registry = Registry::new(&config.file.registry).ok_or_else(|| {
+            anyhow!("Ownership isn't tracked, set `owner` in the [registry] section of the config first")
+        })?;
+
+        let mut refused = 0;
+        for watcher in config.watchers.iter_mut() {
+            let matches = should_watch(
+                watcher,
+                self.interface.as_ref(),
+                self.record.as_ref(),
+                self.tag.as_ref(),
+            );
+            if !matches {
+                continue;
+            }
+            for record in watcher.watching.iter().flat_map(|zone| zone.records.iter()) {
+                let name = format!("{} ({})", &record.name, &record.ty);
+                let existing =
+                    watcher
+                        .client
+                        .list(&record.zone_id, Some(&record.name), Some(record.ty))?;
+                if existing.is_empty() {
+                    println!(
+                        "{}: doesn't exist yet, and will be owned once created",
+                        name
+                    );
+                    continue;
+                }
+                match registry.claim(&watcher.client, record, self.force) {
+                    Ok(Ownership::Owned) => println!("{}: already owned", name),
+                    Ok(Ownership::Unowned) => println!("{}: adopted", name),
+                    Ok(Ownership::Foreign(owner)) => {
+                        println!("{}: taken over from '{}'", name, owner)
+                    }
+                    Err(e) if e.is::<NotOwned>() => {
+                        refused += 1;
+                        println!("{}, use --force to take it over", e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if refused > 0 {
+            bail!(
+                "{} record(s) are owned by other instances, and were not adopted",
+                refused
+            );
+        }
+        println!("Records are owned by '{}'", registry.owner());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use clap::Args;
+
+use super::output::{or_none, print_rows, Format};
+use super::Command;
+use crate::config::Config;
+use crate::control::{self, Request, Response};
+use crate::health::DaemonState;
+
+#[derive(Args)]
+pub struct Status {
+    /// The format in which to print the state of the daemon
+    #[clap(short, long, arg_enum, default_value = "table")]
+    format: Format,
+}
+
+impl Command for Status {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        match send(Request::Status)? {
+            Response::Status { state } => self.format.print(&*state, print_table),
+            response => print_response(response),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Trigger {
+    /// Only sync the watcher for this interface
+    #[clap(short, long)]
+    interface: Option<String>,
+    /// Only sync the watchers which manage this record, note that all of their records are synced
+    #[clap(short, long)]
+    record: Option<String>,
+}
+
+impl Command for Trigger {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        print_response(send(Request::Trigger {
+            interface: self.interface.clone(),
+            record: self.record.clone(),
+        })?)
+    }
+}
+
+#[derive(Args)]
+pub struct Pause {
+    /// The name of the record to stop syncing
+    record: String,
+}
+
+impl Command for Pause {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        print_response(send(Request::Pause {
+            record: self.record.clone(),
+        })?)
+    }
+}
+
+#[derive(Args)]
+pub struct Resume {
+    /// The name of the record to resume syncing
+    record: String,
+}
+
+impl Command for Resume {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        print_response(send(Request::Resume {
+            record: self.record.clone(),
+        })?)
+    }
+}
+
+#[derive(Args)]
+pub struct Reload;
+
+impl Command for Reload {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        print_response(send(Request::Reload)?)
+    }
+}
+
+fn send(request: Request) -> anyhow::Result<Response> {
+    control::request(&control::path()?, &request)
+}
+
+/// Prints the message in `response`, returning an error if the request failed
+fn print_response(response: Response) -> anyhow::Result<()> {
+    match response {
+        Response::Ok { message } => {
+            println!("{}", message);
+            Ok(())
+        }
+        Response::Error { message } => Err(anyhow::anyhow!(message)),
+        Response::Status { .. } => Err(anyhow::anyhow!("Received an unexpected response")),
+    }
+}
+
+fn print_table(state: &DaemonState) {
+    println!(
+        "PID: {}, healthy: {}, updated: {}",
+        state.pid, state.healthy, &state.updated
+    );
+    if !state.paused.is_empty() {
+        println!("Paused: {}", state.paused.join(", "));
+    }
+    println!();
+
+    let watchers = state
+        .watchers
+        .iter()
+        .map(|w| {
+            vec![
+                w.report.interface.clone(),
+                w.zones.join(","),
+                or_none(w.report.ipv4),
+                or_none(w.report.ipv6),
+                w.report.interval.to_string(),
+                format!("{:?}", &w.report.status),
+                w.health.to_string(),
+                w.polls.consecutive_failures.to_string(),
+                or_none(w.polls.last_success),
+                or_none(w.polls.next_attempt),
+            ]
+        })
+        .collect();
+    print_rows(
+        &[
+            "INTERFACE",
+            "ZONES",
+            "IPV4",
+            "IPV6",
+            "INTERVAL",
+            "STATUS",
+            "HEALTH",
+            "FAILURES",
+            "LAST SUCCESS",
+            "NEXT ATTEMPT",
+        ],
+        watchers,
+    );
+
+    let records = state
+        .watchers
+        .iter()
+        .flat_map(|w| w.report.records.iter().map(move |r| (w, r)))
+        .map(|(w, record)| {
+            vec![
+                w.report.interface.clone(),
+                record.name.clone(),
+                record.ty.to_string(),
+                record.local.to_string(),
+                or_none(record.upstream.as_ref()),
+                record.status.to_string(),
+                or_none(record.changed),
+                state.paused.contains(&record.name).to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    if !records.is_empty() {
+        println!();
+        print_rows(
+            &[
+                "INTERFACE",
+                "NAME",
+                "TYPE",
+                "LOCAL",
+                "UPSTREAM",
+                "STATUS",
+                "CHANGED",
+                "PAUSED",
+            ],
+            records,
+        );
+    }
+
+    // Errors are too long to fit in a column, so are printed separately
+    for watcher in state.watchers.iter() {
+        if let Some(error) = watcher.polls.last_error.as_ref() {
+            println!();
+            println!("{}: last error: {}", &watcher.report.interface, error);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use clap::Args;
+
+use super::Command;
+use crate::audit::{self, AuditEntry};
+use crate::cloudflare::{DnsContent, DnsRecordType};
+use crate::config::Config;
+use crate::time::Timestamp;
+
+#[derive(Args)]
+pub struct History {
+    /// Only show changes to the record with this name
+    #[clap(short, long)]
+    record: Option<String>,
+    /// Only show changes made at or after this time, either a timestamp, or a duration ago (e.g. 7d)
+    #[clap(long)]
+    since: Option<Timestamp>,
+    /// Only show changes made before this time, either a timestamp, or a duration ago (e.g. 1h)
+    #[clap(long)]
+    until: Option<Timestamp>,
+}
+
+impl History {
+    /// Returns true if `timestamp` falls within the requested time range
+    fn includes(&self, timestamp: Timestamp) -> bool {
+        let after_since = self.since.map(|since| timestamp >= since).unwrap_or(true);
+        let before_until = self.until.map(|until| timestamp < until).unwrap_or(true);
+        after_since && before_until
+    }
+}
+
+impl Command for History {
+    fn invoke(&self, _config: &mut Config) -> anyhow::Result<()> {
+        let path = audit::path()?;
+        let entries = audit::read(&path)?;
+
+        // Group changes by record, keeping them in the order they were made
+        let mut records = BTreeMap::<(String, DnsRecordType), Vec<AuditEntry>>::new();
+        for entry in entries {
+            if let Some(name) = self.record.as_ref() {
+                if &entry.record != name {
+                    continue;
+                }
+            }
+            records
+                .entry((entry.record.clone(), entry.ty))
+                .or_default()
+                .push(entry);
+        }
+
+        let mut found = false;
+        for ((name, ty), entries) in records.iter() {
+            let mut lines = Vec::new();
+            for entry in entries.iter() {
+                if self.includes(entry.timestamp) {
+                    lines.push(describe(entry, entries));
+                }
+            }
+            if lines.is_empty() {
+                continue;
+            }
+
+            if found {
+                println!();
+            }
+            found = true;
+            println!("{} ({})", name, ty);
+            for line in lines {
+                println!("  {}", line);
+            }
+        }
+
+        if !found {
+            println!("No changes recorded in {}", path.display());
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders a single audit entry, including how long the resulting address lasted
+///
+/// `entries` are all of the changes to records with the same name and type, in the order they were made.
+fn describe(entry: &AuditEntry, entries: &[AuditEntry]) -> String {
+    let old = or_none(entry.old.as_ref());
+    let new = or_none(entry.new.as_ref());
+    let status = entry
+        .status
+        .map(|status| status.to_string())
+        .unwrap_or_else(|| "---".to_string());
+    let change = format!(
+        "{}  {:<6}  {} -> {}  {}  via {}",
+        entry.timestamp,
+        format!("{:?}", entry.action).to_lowercase(),
+        old,
+        new,
+        status,
+        &entry.interface
+    );
+
+    if let Some(error) = entry.error.as_ref() {
+        return format!("{}  failed: {}", change, error);
+    }
+    // A deleted record no longer has an address
+    if entry.new.is_none() {
+        return change;
+    }
+    // Each address lasts until the next successful change to the same record, which may be outside
+    // of the requested time range. Other records with the same name, e.g. duplicates which were
+    // updated or deleted alongside it, don't count.
+    let next = entries
+        .iter()
+        .filter(|e| e.id.is_some() && e.id == entry.id && e.succeeded())
+        .map(|e| e.timestamp)
+        .find(|t| *t > entry.timestamp);
+    match next {
+        Some(next) => format!(
+            "{}  lasted {}",
+            change,
+            format_duration(next.duration_since(entry.timestamp))
+        ),
+        None => format!(
+            "{}  current, for {}",
+            change,
+            format_duration(entry.timestamp.elapsed())
+        ),
+    }
+}
+
+/// Renders the content of a record before or after a change, if it existed
+fn or_none(content: Option<&DnsContent>) -> String {
+    content
+        .map(|content| content.to_string())
+        .unwrap_or_else(|| "(none)".to_string())
+}
+
+/// Formats a duration for display, to the nearest minute for anything longer than a minute
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let rounded = if secs >= 60 { secs - secs % 60 } else { secs };
+    humantime::format_duration(Duration::from_secs(rounded)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(
+        timestamp: &str,
+        action: &str,
+        id: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> AuditEntry {
+        serde_json::from_value(json!({
+            "timestamp": timestamp,
+            "action": action,
+            "interface": "wan0",
+            "zone": "example.com",
+            "record": "foo.example.com",
+            "type": "A",
+            "id": id,
+            "old": old,
+            "new": new,
+            "status": 200,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn an_address_lasts_until_the_next_change() {
+        let entries = [
+            entry("2022-01-01T10:00:00Z", "create", "a", None, Some("1.2.3.4")),
+            entry(
+                "2022-01-05T12:35:00Z",
+                "update",
+                "a",
+                Some("1.2.3.4"),
+                Some("5.6.7.8"),
+            ),
+        ];
+        assert!(describe(&entries[0], &entries).ends_with("lasted 4days 2h 35m"));
+        assert!(describe(&entries[1], &entries).contains("current, for"));
+    }
+
+    #[test]
+    fn failed_changes_dont_end_an_address() {
+        let mut failed = entry(
+            "2022-01-02T10:00:00Z",
+            "update",
+            "a",
+            Some("1.2.3.4"),
+            Some("5.6.7.8"),
+        );
+        failed.error = Some("Unable to reach Cloudflare".to_string());
+        let entries = [
+            entry("2022-01-01T10:00:00Z", "create", "a", None, Some("1.2.3.4")),
+            failed,
+            entry(
+                "2022-01-03T10:00:00Z",
+                "update",
+                "a",
+                Some("1.2.3.4"),
+                Some("5.6.7.8"),
+            ),
+        ];
+        assert!(describe(&entries[0], &entries).ends_with("lasted 2days"));
+        assert!(describe(&entries[1], &entries).ends_with("failed: Unable to reach Cloudflare"));
+    }
+
+    #[test]
+    fn changes_to_duplicates_dont_end_an_address() {
+        let entries = [
+            entry(
+                "2022-01-01T10:00:00Z",
+                "update",
+                "a",
+                Some("1.2.3.4"),
+                Some("5.6.7.8"),
+            ),
+            entry(
+                "2022-01-01T10:00:01Z",
+                "update",
+                "b",
+                Some("1.2.3.4"),
+                Some("5.6.7.8"),
+            ),
+            entry("2022-01-01T10:00:02Z", "delete", "c", Some("1.2.3.4"), None),
+            entry(
+                "2022-01-02T10:00:00Z",
+                "update",
+                "a",
+                Some("5.6.7.8"),
+                Some("9.9.9.9"),
+            ),
+        ];
+        assert!(describe(&entries[0], &entries).ends_with("lasted 1day"));
+        assert!(describe(&entries[1], &entries).contains("current, for"));
+        // A deleted record has no address to report on
+        assert!(describe(&entries[2], &entries).ends_with("via wan0"));
+    }
+
+    #[test]
+    fn rounds_durations_to_the_minute() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m");
+        assert_eq!(format_duration(Duration::from_secs(3600 + 59)), "1h");
+    }
+}
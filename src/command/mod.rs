@@ -1,12 +1,23 @@
+mod adopt;
+mod control;
+mod history;
+mod output;
 mod show;
 mod sync;
+mod token;
 
 use clap::Subcommand;
 
+use crate::cloudflare::DnsRecord;
 use crate::config::Config;
+use crate::watcher::Watcher;
 
+use self::adopt::Adopt;
+use self::control::{Pause, Reload, Resume, Status, Trigger};
+use self::history::History;
 use self::show::Show;
 use self::sync::Sync;
+use self::token::Token;
 
 /// This trait allows for generically invoking commands
 pub trait Command {
@@ -19,18 +30,195 @@ pub trait Command {
 
 #[derive(Subcommand)]
 pub(crate) enum Commands {
+    /// Takes ownership of existing records in Cloudflare, so that they can be synced
+    Adopt(Adopt),
+    /// Shows the changes made to DNS records over time, from the audit log
+    History(History),
     /// Summarizes the configuration and current synchronization status
     Show(Show),
     /// Synchronize DNS records based on the current configuration
     Sync(Sync),
+    /// Manages the API tokens used to access Cloudflare
+    Token(Token),
+    /// Shows the status of the running daemon
+    Status(Status),
+    /// Asks the running daemon to sync immediately
+    Trigger(Trigger),
+    /// Asks the running daemon to stop syncing a record
+    Pause(Pause),
+    /// Asks the running daemon to resume syncing a paused record
+    Resume(Resume),
+    /// Asks the running daemon to reload its configuration
+    Reload(Reload),
+}
+
+impl Commands {
+    /// Returns true if this command operates on watchers, which requires resolving zones and interfaces
+    pub fn requires_watchers(&self) -> bool {
+        matches!(self, Self::Adopt(_) | Self::Show(_) | Self::Sync(_))
+    }
+
+    /// Returns the interface this command is limited to, if any, so that only what it uses is loaded
+    pub fn interface(&self) -> Option<&str> {
+        match self {
+            Self::Adopt(c) => c.interface.as_deref(),
+            Self::Sync(c) => c.interface.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 impl Command for Commands {
     #[inline]
     fn invoke(&self, config: &mut Config) -> anyhow::Result<()> {
         match self {
+            Self::Adopt(c) => c.invoke(config),
+            Self::History(c) => c.invoke(config),
             Self::Show(c) => c.invoke(config),
             Self::Sync(c) => c.invoke(config),
+            Self::Token(c) => c.invoke(config),
+            Self::Status(c) => c.invoke(config),
+            Self::Trigger(c) => c.invoke(config),
+            Self::Pause(c) => c.invoke(config),
+            Self::Resume(c) => c.invoke(config),
+            Self::Reload(c) => c.invoke(config),
         }
     }
 }
+
+/// Returns true if `watcher` should be used, given the filters passed to a command
+///
+/// Records which don't match the filters are removed from the watcher.
+fn should_watch(
+    watcher: &mut Watcher,
+    interface: Option<&String>,
+    record: Option<&String>,
+    tag: Option<&String>,
+) -> bool {
+    if let Some(iface) = interface {
+        if watcher.interface.name != iface.as_str() {
+            return false;
+        }
+    }
+
+    if record.is_some() || tag.is_some() {
+        let mut zones = Vec::new();
+        for mut zone in watcher.watching.drain(0..) {
+            zone.records = zone
+                .records
+                .drain(0..)
+                .filter(|r| record.is_none_or(|rec| r.name == *rec))
+                .filter(|r| tag.is_none_or(|tag| has_tag(r, tag)))
+                .collect::<Vec<_>>();
+            if !zone.records.is_empty() {
+                zones.push(zone);
+            }
+        }
+        watcher.watching = zones;
+
+        return !watcher.watching.is_empty();
+    }
+
+    true
+}
+
+/// Returns true if `record` is configured with `tag`, which may be a full `name:value` tag, or just a name
+fn has_tag(record: &DnsRecord, tag: &str) -> bool {
+    record
+        .tags
+        .iter()
+        .flatten()
+        .any(|t| t == tag || t.split_once(':').map(|(name, _)| name) == Some(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::cloudflare::{Cloudflare, Credentials, Zone};
+
+    fn record(name: &str, tags: &[&str]) -> DnsRecord {
+        serde_json::from_value(json!({
+            "zone_id": "zone",
+            "name": name,
+            "type": "A",
+            "content": "1.2.3.4",
+            "tags": tags,
+        }))
+        .unwrap()
+    }
+
+    fn watcher(records: Vec<DnsRecord>) -> Watcher {
+        let interface = serde_json::from_value(json!({ "name": "eth0", "interval": 60 })).unwrap();
+        let client = Cloudflare::new(&Credentials::default()).unwrap();
+        let mut watcher = Watcher::new(interface, Credentials::default(), client);
+        let mut zone: Zone =
+            serde_json::from_value(json!({ "id": "zone", "name": "example.com" })).unwrap();
+        zone.records = records;
+        watcher.watching.push(zone);
+        watcher
+    }
+
+    fn names(watcher: &Watcher) -> Vec<&str> {
+        watcher
+            .watching
+            .iter()
+            .flat_map(|zone| zone.records.iter())
+            .map(|record| record.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn matches_tags_by_name_and_value_or_just_name() {
+        let tagged = record("foo.example.com", &["env:prod", "managed"]);
+
+        assert!(has_tag(&tagged, "env:prod"));
+        assert!(has_tag(&tagged, "env"));
+        assert!(has_tag(&tagged, "managed"));
+        assert!(!has_tag(&tagged, "env:dev"));
+        assert!(!has_tag(&tagged, "prod"));
+        assert!(!has_tag(&tagged, "en"));
+        assert!(!has_tag(&tagged, "managed:yes"));
+        assert!(!has_tag(&record("bar.example.com", &[]), "env"));
+    }
+
+    #[test]
+    fn only_watches_the_matching_records() {
+        let records = || {
+            vec![
+                record("foo.example.com", &["env:prod"]),
+                record("bar.example.com", &["env:dev"]),
+            ]
+        };
+        let (eth0, eth1) = ("eth0".to_string(), "eth1".to_string());
+        let (bar, prod) = ("bar.example.com".to_string(), "env:prod".to_string());
+        let env = "env".to_string();
+
+        let mut unfiltered = watcher(records());
+        assert!(should_watch(&mut unfiltered, Some(&eth0), None, None));
+        assert_eq!(names(&unfiltered), ["foo.example.com", "bar.example.com"]);
+
+        assert!(!should_watch(
+            &mut watcher(records()),
+            Some(&eth1),
+            None,
+            None
+        ));
+
+        let mut by_record = watcher(records());
+        assert!(should_watch(&mut by_record, None, Some(&bar), None));
+        assert_eq!(names(&by_record), ["bar.example.com"]);
+
+        let mut by_tag = watcher(records());
+        assert!(should_watch(&mut by_tag, None, None, Some(&prod)));
+        assert_eq!(names(&by_tag), ["foo.example.com"]);
+
+        let mut by_tag_name = watcher(records());
+        assert!(should_watch(&mut by_tag_name, None, None, Some(&env)));
+        assert_eq!(names(&by_tag_name), ["foo.example.com", "bar.example.com"]);
+
+        let mut neither = watcher(records());
+        assert!(!should_watch(&mut neither, None, Some(&bar), Some(&prod)));
+    }
+}
@@ -0,0 +1,145 @@
+//! This module implements the formats in which commands can print structured output.
+use clap::ArgEnum;
+use serde::Serialize;
+
+#[derive(Copy, Clone, ArgEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    Table,
+}
+impl Format {
+    /// Prints `value` in this format, using `table` to render it as a table
+    pub fn print<T, F>(self, value: &T, table: F) -> anyhow::Result<()>
+    where
+        T: Serialize,
+        F: FnOnce(&T),
+    {
+        match self.render(value)? {
+            Some(rendered) => print!("{}", rendered),
+            None => table(value),
+        }
+        Ok(())
+    }
+
+    /// Renders `value` in this format, or returns `None` if it must be rendered as a table instead
+    fn render<T: Serialize>(self, value: &T) -> anyhow::Result<Option<String>> {
+        let rendered = match self {
+            Self::Json => format!("{}\n", serde_json::to_string_pretty(value)?),
+            Self::Yaml => serde_yaml::to_string(value)?,
+            // Converting to a TOML value first ensures tables are emitted after plain values
+            Self::Toml => toml::to_string(&toml::Value::try_from(value)?)?,
+            Self::Table => return Ok(None),
+        };
+        Ok(Some(rendered))
+    }
+}
+
+/// Prints `rows` as a table, with each column padded to the width of its widest cell
+pub fn print_rows(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let headers = headers.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+    for row in std::iter::once(&headers).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Renders an optional value for display in a table
+pub fn or_none<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::health::DaemonState;
+
+    /// The state of a daemon with two watchers for the same interface, one of which is failing
+    fn state() -> DaemonState {
+        let watcher = |zone: &str, error: Option<&str>| {
+            json!({
+                "interface": "eth8",
+                "ipv4": "1.2.3.4",
+                "ipv6": null,
+                "interval": 60,
+                "status": if error.is_some() { "Failed" } else { "Synced" },
+                "records": [{
+                    "name": format!("foo.{}", zone),
+                    "zone": "zone",
+                    "type": "A",
+                    "local": "1.2.3.4",
+                    "upstream": null,
+                    "status": "Error",
+                    "detail": "Unable to reach Cloudflare",
+                    "proxied": false,
+                    "ttl": 1,
+                }],
+                "zones": [zone],
+                "health": if error.is_some() { "Failing" } else { "Healthy" },
+                "consecutive_failures": if error.is_some() { 1 } else { 0 },
+                "last_attempt": null,
+                "last_success": null,
+                "last_error": error,
+                "next_attempt": null,
+            })
+        };
+        serde_json::from_value(json!({
+            "pid": 42,
+            "updated": "2022-01-01T10:00:00Z",
+            "heartbeat": "2022-01-01T10:00:00Z",
+            "healthy": true,
+            "started": true,
+            "paused": ["foo.example.com"],
+            "watchers": [
+                watcher("example.com", None),
+                watcher("example.org", Some("failed: \"quoted\"\nand more")),
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_valid_toml() {
+        let rendered = Format::Toml.render(&state()).unwrap().unwrap();
+        let parsed: toml::Value = toml::from_str(&rendered).unwrap();
+        assert_eq!(parsed["paused"][0].as_str(), Some("foo.example.com"));
+        // Watchers for the same interface don't clash
+        let watchers = parsed["watchers"].as_array().unwrap();
+        assert_eq!(watchers.len(), 2);
+        assert_eq!(
+            watchers[1]["last_error"].as_str(),
+            Some("failed: \"quoted\"\nand more")
+        );
+    }
+
+    #[test]
+    fn renders_json_and_yaml() {
+        let state = state();
+        let json = Format::Json.render(&state).unwrap().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["watchers"][1]["zones"], json!(["example.org"]));
+
+        let yaml = Format::Yaml.render(&state).unwrap().unwrap();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed["pid"].as_u64(), Some(42));
+
+        assert!(Format::Table.render(&state).unwrap().is_none());
+    }
+}
@@ -1,39 +1,41 @@
+use anyhow::{anyhow, bail};
 use clap::Args;
+use log::warn;
+use serde::Serialize;
 
+use super::output::{or_none, print_rows, Format};
 use super::Command;
-use crate::cloudflare::{DnsContent, DnsRecordType, Id, ProxyMode, Ttl};
+use crate::cloudflare::DnsRecord;
 use crate::config::Config;
-use crate::system::AddressFamily;
+use crate::health::{DaemonState, Health, HealthStatus};
+use crate::reconcile;
+use crate::status::{SyncStatus, WatcherReport, WatcherStatus};
 
 #[derive(Args)]
-pub struct Show;
-
-#[derive(Debug)]
-enum WatcherStatus {
-    Disabled,
-    Synced,
-    OutOfSync,
-    Failed,
+pub struct Show {
+    /// The format in which to print the status of each watcher
+    #[clap(short, long, arg_enum, default_value = "table")]
+    format: Format,
 }
 
-#[derive(Debug)]
-enum CloudflareStatus {
-    OK,
-    Missing,
-    TypeMismatch(DnsRecordType),
-    OutOfSync,
-    Error(String),
+/// The status of all configured watchers, as printed by `show`
+#[derive(Serialize)]
+struct Output {
+    watchers: Vec<WatcherOutput>,
 }
 
-struct SyncStatus {
-    name: String,
-    zone: Id,
-    ty: DnsRecordType,
-    local: DnsContent,
-    upstream: Option<DnsContent>,
-    status: CloudflareStatus,
-    proxied: ProxyMode,
-    ttl: Ttl,
+/// The status of a single watcher, along with its health, if a daemon is running (or has run)
+#[derive(Serialize)]
+struct WatcherOutput {
+    #[serde(flatten)]
+    report: WatcherReport,
+    zones: Vec<String>,
+    /// The kind of credentials used to access Cloudflare
+    auth: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<HealthStatus>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    polls: Option<Health>,
 }
 
 impl Command for Show {
@@ -43,101 +45,184 @@ impl Command for Show {
             return Ok(());
         }
 
-        // Print the current v4 address for each configured interface, alongside other useful info
-        for (index, watcher) in config.watchers.iter().enumerate() {
-            let name = watcher.interface.name.as_str();
-            let interval = watcher.interface.interval;
-            let info = &watcher.interface.info;
-
-            // For formatting, start each section with a newline after the first has been printed
-            if index > 0 {
-                println!();
-            }
-
-            println!("[{}]", name);
-            if let Some(v4) = info.address(AddressFamily::IPv4) {
-                println!("ipv4     = \"{}\"", v4);
-            }
-            if let Some(v6) = info.address(AddressFamily::IPv6) {
-                println!("ipv6     = \"{}\"", v6);
+        // If a daemon is running (or has run), we can also report on the health of each watcher
+        let daemon = match DaemonState::path().and_then(|path| DaemonState::load(&path)) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Unable to load daemon state: {:#}", e);
+                None
             }
-            println!("interval = {}", &interval);
+        };
 
-            let mut status = WatcherStatus::Synced;
+        // Compare the desired state of each record against what is in Cloudflare
+        let mut watchers = Vec::new();
+        for watcher in config.watchers.iter() {
             let mut records = Vec::new();
             for zone in watcher.watching.iter() {
+                // Records of any type are listed, so that one of another type in the way can be reported
+                let listed = reconcile::list(&watcher.client, zone, false);
                 for record in zone.records.iter() {
-                    let mut sync = SyncStatus {
-                        name: record.name.clone(),
-                        zone: zone.id.clone(),
-                        ty: record.ty,
-                        local: record.content.clone(),
-                        upstream: None,
-                        status: CloudflareStatus::Missing,
-                        proxied: ProxyMode::default(),
-                        ttl: Ttl::default(),
+                    let upstream = match listed.as_ref() {
+                        Ok(listed) => Ok(matching(record, listed).into_iter().cloned().collect()),
+                        Err(e) => Err(anyhow!("{}", e)),
                     };
-                    match watcher.client.get_by_name(&zone.id, &record.name) {
-                        Ok(None) => {}
-                        Ok(Some(upstream)) => {
-                            sync.proxied = upstream.proxied;
-                            sync.ttl = upstream.ttl;
-                            if sync.ty != upstream.ty {
-                                sync.status = CloudflareStatus::TypeMismatch(upstream.ty);
-                                sync.upstream = Some(upstream.content);
-                            } else if sync.local == upstream.content {
-                                sync.status = CloudflareStatus::OK;
-                                sync.upstream = Some(upstream.content);
-                            } else {
-                                sync.status = CloudflareStatus::OutOfSync;
-                                sync.upstream = Some(upstream.content);
-                            }
-                        }
-                        Err(e) => {
-                            sync.status = CloudflareStatus::Error(format!("{}", &e));
-                        }
-                    }
-                    match &sync.status {
-                        CloudflareStatus::Error(_) => {
-                            status = WatcherStatus::Failed;
-                        }
-                        CloudflareStatus::OK => {}
-                        _ => {
-                            status = WatcherStatus::OutOfSync;
-                        }
-                    }
-                    records.push(sync);
+                    records.push(SyncStatus::compare(record, upstream));
                 }
             }
-            // If there are no records to sync, the watcher is disabled automatically
-            if records.is_empty() {
-                status = WatcherStatus::Disabled;
-            }
-            println!("status   = \"{:?}\"", &status);
+            let health = daemon
+                .as_ref()
+                .and_then(|state| state.watchers.iter().find(|w| w.is_for(watcher)));
+            watchers.push(WatcherOutput {
+                report: WatcherReport::new(watcher, records),
+                zones: watcher.watching.iter().map(|z| z.name.clone()).collect(),
+                auth: watcher.credentials.kind(),
+                health: health.map(|h| h.health),
+                polls: health.map(|h| h.polls.clone()),
+            });
+        }
+        let output = Output { watchers };
 
-            for zone in watcher.watching.iter() {
-                if records.is_empty() {
-                    continue;
-                }
-                for record in records.iter().filter(|r| r.zone == zone.id) {
-                    println!();
-                    println!("[[{}.zones.\"{}\"]]", name, &zone.name);
-                    let upstream = record
-                        .upstream
-                        .as_ref()
-                        .map(|content| content.to_string())
-                        .unwrap_or_else(|| "N/A".to_string());
-                    println!("name      = \"{}\"", &record.name);
-                    println!("type      = \"{}\"", &record.ty);
-                    println!("local     = \"{}\"", &record.local);
-                    println!("upstream  = \"{}\"", &upstream);
-                    println!("proxied   = {}", &record.proxied.as_bool());
-                    println!("ttl       = {}", &record.ttl);
-                    println!("status    = \"{:?}\"", &record.status);
-                }
-            }
+        self.format
+            .print(&output, |output| print_table(config, output))?;
+
+        // Exit with an error if anything needs attention, so that scripts and monitoring can tell
+        let unsynced = output
+            .watchers
+            .iter()
+            .filter(|w| {
+                matches!(
+                    w.report.status,
+                    WatcherStatus::OutOfSync | WatcherStatus::Failed
+                )
+            })
+            .count();
+        if unsynced > 0 {
+            bail!("{} watcher(s) are out of sync or have failed", unsynced);
         }
 
         Ok(())
     }
 }
+
+/// Finds the records in `listed` which correspond to `record`
+///
+/// These are all of the records with the same name and type, including any duplicates, or if there
+/// are none, a single record of another type with the same name.
+fn matching<'a>(record: &DnsRecord, listed: &'a [DnsRecord]) -> Vec<&'a DnsRecord> {
+    let named = listed.iter().filter(|r| r.name == record.name);
+    let same = named
+        .clone()
+        .filter(|r| r.ty == record.ty)
+        .collect::<Vec<_>>();
+    if same.is_empty() {
+        named.rev().take(1).collect()
+    } else {
+        same
+    }
+}
+
+fn print_table(config: &Config, output: &Output) {
+    let watchers = output
+        .watchers
+        .iter()
+        .map(|w| {
+            let polls = w.polls.as_ref();
+            vec![
+                w.report.interface.clone(),
+                or_none(w.report.ipv4),
+                or_none(w.report.ipv6),
+                w.report.interval.to_string(),
+                format!("{:?}", &w.report.status),
+                w.auth.to_string(),
+                or_none(w.health),
+                or_none(polls.map(|p| p.consecutive_failures)),
+                or_none(polls.and_then(|p| p.last_success)),
+                or_none(polls.and_then(|p| p.next_attempt)),
+            ]
+        })
+        .collect();
+    print_rows(
+        &[
+            "INTERFACE",
+            "IPV4",
+            "IPV6",
+            "INTERVAL",
+            "STATUS",
+            "AUTH",
+            "HEALTH",
+            "FAILURES",
+            "LAST SUCCESS",
+            "NEXT ATTEMPT",
+        ],
+        watchers,
+    );
+
+    let mut records = Vec::new();
+    for (watcher, output) in config.watchers.iter().zip(output.watchers.iter()) {
+        for record in output.report.records.iter() {
+            let zone = watcher
+                .watching
+                .iter()
+                .find(|z| z.id == record.zone)
+                .map(|z| z.name.clone())
+                .unwrap_or_else(|| record.zone.to_string());
+            records.push(vec![
+                output.report.interface.clone(),
+                zone,
+                record.name.clone(),
+                record.ty.to_string(),
+                record.local.to_string(),
+                or_none(record.upstream.as_ref()),
+                record.proxied.as_bool().to_string(),
+                record.ttl.to_string(),
+                record.status.to_string(),
+                or_none(Some(record.tags.join(",")).filter(|tags| !tags.is_empty())),
+                or_none(record.comment.as_ref()),
+            ]);
+        }
+    }
+    if !records.is_empty() {
+        println!();
+        print_rows(
+            &[
+                "INTERFACE",
+                "ZONE",
+                "NAME",
+                "TYPE",
+                "LOCAL",
+                "UPSTREAM",
+                "PROXIED",
+                "TTL",
+                "STATUS",
+                "TAGS",
+                "COMMENT",
+            ],
+            records,
+        );
+    }
+
+    // Errors and warnings are too long to fit in a column, so are printed separately
+    for watcher in output.watchers.iter() {
+        if watcher.auth == "global_api_key" {
+            println!();
+            println!(
+                "{}: uses a Global API Key, consider using a scoped API token instead",
+                &watcher.report.interface
+            );
+        }
+        for record in watcher.report.records.iter() {
+            if let Some(count) = record.duplicates {
+                println!();
+                println!(
+                    "{}: {} has {} {} records in Cloudflare",
+                    &watcher.report.interface, &record.name, count, &record.ty
+                );
+            }
+        }
+        let error = watcher.polls.as_ref().and_then(|p| p.last_error.as_ref());
+        if let Some(error) = error {
+            println!();
+            println!("{}: last error: {}", &watcher.report.interface, error);
+        }
+    }
+}
@@ -1,12 +1,17 @@
-use std::thread;
+use std::time::Duration;
 
-use clap::Args;
+use clap::{ArgEnum, Args};
 use log::{info, warn};
 
+use crate::config::file::Contention;
 use crate::config::Config;
+use crate::daemon::Daemon;
+use crate::lock::InstanceLock;
+use crate::notify;
+use crate::plan::{Operation, RecordPlan};
 use crate::watcher::Watcher;
 
-use super::Command;
+use super::{should_watch, Command};
 
 #[derive(Args)]
 pub struct Sync {
@@ -15,25 +20,76 @@ pub struct Sync {
     daemon: bool,
     /// Only sync records bound to a specific interface
     #[clap(short, long)]
-    interface: Option<String>,
+    pub(super) interface: Option<String>,
     /// Only sync records with the given name
     #[clap(short, long)]
     record: Option<String>,
+    /// Only sync records with the given tag, either `name:value`, or just its name
+    #[clap(short, long)]
+    tag: Option<String>,
+    /// What to do if another instance is already syncing, overriding the config file
+    #[clap(long, arg_enum)]
+    on_contention: Option<Contention>,
+    /// Show what would be changed in Cloudflare, without changing anything
+    #[clap(long, conflicts_with = "daemon")]
+    dry_run: bool,
+    /// The format in which to print the plan for a dry run, defaults to text
+    #[clap(long, arg_enum, requires = "dry-run")]
+    format: Option<PlanFormat>,
+}
+
+#[derive(Copy, Clone, ArgEnum)]
+enum PlanFormat {
+    Text,
+    Json,
 }
 
 impl Command for Sync {
     fn invoke(&self, config: &mut Config) -> anyhow::Result<()> {
+        // The daemon keeps running without watchers, since they may be added to the config later
         if config.watchers.is_empty() {
-            warn!("No watchers configured, nothing to do!");
-            return Ok(());
+            if !self.daemon {
+                warn!("No watchers configured, nothing to do!");
+                return Ok(());
+            }
+            warn!(
+                "No watchers configured, waiting for them to be added to {}",
+                config.path.display()
+            );
         }
 
+        if self.dry_run {
+            return self.plan(config);
+        }
+
+        // Hold the lock until we're done, so that overlapping syncs don't race to create records
+        let on_contention = self.on_contention.unwrap_or(config.file.lock.on_contention);
+        let timeout = Duration::from_secs(config.file.lock.timeout as u64);
+        let _lock = match InstanceLock::acquire(&InstanceLock::path()?, on_contention, timeout)? {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+
+        notify::configure(&config.file.notifications)?;
+
         // If not running as a daemon, simply poll each matching watcher once, then terminate
         if !self.daemon {
             info!("Performing a one-time sync");
+            let mut result = Ok(());
             for watcher in config.watchers.iter_mut() {
-                if should_watch(watcher, self.interface.as_ref(), self.record.as_ref()) {
-                    watcher.poll()?;
+                if self.should_watch(watcher) {
+                    result = watcher.poll(&config.ifconfig);
+                    if let Err(e) = result.as_ref() {
+                        // There's no failure threshold for a one-time sync, so any failure is reported
+                        notify::emit(notify::Event::SyncFailing {
+                            interface: watcher.interface.name.clone(),
+                            failures: 1,
+                            unhealthy: true,
+                            error: format!("{:#}", e),
+                            records: watcher.failing(),
+                        });
+                        break;
+                    }
                 } else {
                     info!(
                         "Skipping watcher for {}, no records to sync",
@@ -41,72 +97,110 @@ impl Command for Sync {
                     );
                 }
             }
-            return Ok(());
+            // Make sure any notifications are delivered before we exit
+            notify::shutdown();
+            return result;
         }
 
-        // Otherwise, we are going to spawn a thread for each watcher
-        // Each watcher will poll once, then sleep for its configured interval.
-        info!("Starting daemon");
-
-        let mut threads = Vec::new();
-        for mut watcher in config.watchers.drain(0..) {
-            if !should_watch(&mut watcher, self.interface.as_ref(), self.record.as_ref()) {
-                info!(
-                    "Skipping watcher for {}, no records to sync",
-                    &watcher.interface.name
-                );
-                continue;
-            }
-            info!("Starting thread for {} watcher", &watcher.interface.name);
-            let handle = thread::spawn(move || {
-                let interval = watcher.interface.interval;
+        // Otherwise, we run all watchers from a single scheduler, and reload them when the config changes.
+        // Each watcher will poll once, then wait for its configured interval before polling again.
+        let config = std::mem::take(config);
+        Daemon::new(|watcher| self.should_watch(watcher))?.run(config)
+    }
+}
 
-                loop {
-                    let _ = watcher.poll();
-                    thread::sleep(interval.duration());
-                }
-            });
-            threads.push(handle);
-        }
+impl Sync {
+    /// Returns true if `watcher` has records matching the filters given on the command line
+    ///
+    /// Records which don't match are removed from the watcher.
+    fn should_watch(&self, watcher: &mut Watcher) -> bool {
+        should_watch(
+            watcher,
+            self.interface.as_ref(),
+            self.record.as_ref(),
+            self.tag.as_ref(),
+        )
+    }
 
-        for handle in threads.drain(0..) {
-            if let Err(e) = handle.join() {
-                std::panic::resume_unwind(e);
+    /// Prints the changes a one-time sync would make, without making them
+    fn plan(&self, config: &mut Config) -> anyhow::Result<()> {
+        let mut plans = Vec::new();
+        for watcher in config.watchers.iter_mut() {
+            if self.should_watch(watcher) {
+                plans.extend(watcher.plan(&config.ifconfig)?);
             }
         }
 
+        match self.format.unwrap_or(PlanFormat::Text) {
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&plans)?),
+            PlanFormat::Text => print_plan(&plans),
+        }
+
         Ok(())
     }
 }
 
-fn should_watch(
-    watcher: &mut Watcher,
-    interface: Option<&String>,
-    record: Option<&String>,
-) -> bool {
-    if let Some(iface) = interface {
-        if watcher.interface.name != iface.as_str() {
-            return false;
+fn print_plan(plans: &[RecordPlan]) {
+    let (mut create, mut update, mut delete, mut unchanged, mut skipped) = (0, 0, 0, 0, 0);
+    for (index, plan) in plans.iter().enumerate() {
+        // Start a new section whenever the interface or zone changes
+        let previous = index.checked_sub(1).map(|i| &plans[i]);
+        if previous.map(|p| (&p.interface, &p.zone)) != Some((&plan.interface, &plan.zone)) {
+            if previous.is_some() {
+                println!();
+            }
+            println!("{} ({})", &plan.zone, &plan.interface);
         }
-    }
 
-    if let Some(rec) = record {
-        let rec = rec.as_str();
-        let mut zones = Vec::new();
-        for mut zone in watcher.watching.drain(0..) {
-            zone.records = zone
-                .records
-                .drain(0..)
-                .filter(|r| r.name == rec)
-                .collect::<Vec<_>>();
-            if !zone.records.is_empty() {
-                zones.push(zone);
+        let record = format!("{} ({})", &plan.name, &plan.ty);
+        match &plan.operation {
+            Operation::Create {
+                content,
+                proxied,
+                ttl,
+                comment,
+                tags,
+            } => {
+                create += 1;
+                println!("  + {} will be created", record);
+                println!("      content: \"{}\"", content);
+                println!("      proxied: {}", proxied.as_bool());
+                println!("      ttl: {}", ttl);
+                if let Some(comment) = comment {
+                    println!("      comment: {:?}", comment);
+                }
+                if let Some(tags) = tags {
+                    println!("      tags: {:?}", tags);
+                }
+            }
+            Operation::Update { changes, .. } => {
+                update += 1;
+                println!("  ~ {} will be updated", record);
+                for change in changes.iter() {
+                    println!("      {}", change);
+                }
+            }
+            Operation::Delete { content, .. } => {
+                delete += 1;
+                println!("  - {} will be deleted, as a duplicate", record);
+                println!("      content: \"{}\"", content);
+            }
+            Operation::NoOp => {
+                unchanged += 1;
+                println!("    {} is up to date", record);
+            }
+            Operation::Skip { reason } => {
+                skipped += 1;
+                println!("  ! {} will be skipped: {}", record, reason);
             }
         }
-        watcher.watching = zones;
-
-        return !watcher.watching.is_empty();
     }
 
-    true
+    if !plans.is_empty() {
+        println!();
+    }
+    println!(
+        "Plan: {} to create, {} to update, {} to delete, {} unchanged, {} skipped",
+        create, update, delete, unchanged, skipped
+    );
 }
@@ -0,0 +1,80 @@
+use anyhow::bail;
+use clap::{Args, Subcommand};
+
+use super::Command;
+use crate::config::Config;
+use crate::preflight;
+
+#[derive(Args)]
+pub struct Token {
+    #[clap(subcommand)]
+    command: TokenCommand,
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Checks that the token for each zone is valid, and has the permissions cfdns needs
+    Verify(Verify),
+}
+
+impl Command for Token {
+    fn invoke(&self, config: &mut Config) -> anyhow::Result<()> {
+        match &self.command {
+            TokenCommand::Verify(c) => c.invoke(config),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Verify {
+    /// Only verify the token for the zone with this name
+    #[clap(short, long)]
+    zone: Option<String>,
+}
+
+impl Command for Verify {
+    fn invoke(&self, config: &mut Config) -> anyhow::Result<()> {
+        let zones = config
+            .file
+            .zones
+            .iter()
+            .filter(|z| {
+                self.zone
+                    .as_ref()
+                    .map(|name| &z.name == name)
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        if zones.is_empty() {
+            bail!("No matching zones configured");
+        }
+
+        let mut failed = 0;
+        for (index, zone) in zones.into_iter().enumerate() {
+            let report = preflight::check(zone, zone.credentials());
+            if index > 0 {
+                println!();
+            }
+            println!("{} ({})", &report.zone, &report.auth);
+            for check in report.checks.iter() {
+                println!(
+                    "  {:<9} {:<8} {}",
+                    check.name,
+                    check.outcome.label(),
+                    &check.outcome
+                );
+            }
+            if report.failed() || report.inconclusive() {
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            bail!(
+                "The credentials for {} zone(s) could not be verified",
+                failed
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,38 +1,434 @@
 use std::env;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 
-use crate::cloudflare::{DnsRecordType, Id, ProxyMode, Ttl};
+use crate::cloudflare::{Credentials, DnsRecordType, Id, ProxyMode, Ttl};
+use crate::notify::EventKind;
+use crate::secret::Secret;
 
 use super::Interface;
 
 #[derive(Default, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ConfigFile {
+    pub daemon: DaemonConfig,
+    /// Hooks which run for changes to any record
+    pub hooks: HookConfig,
+    pub http: HttpConfig,
     pub interfaces: Vec<Interface>,
+    pub lock: LockConfig,
+    pub mqtt: Option<MqttConfig>,
+    pub notifications: Vec<NotificationConfig>,
     pub records: Vec<RecordConfig>,
+    pub registry: RegistryConfig,
     pub zones: Vec<ZoneConfig>,
 }
 impl ConfigFile {
     pub fn zone(&self, name: &str) -> Option<&ZoneConfig> {
-        for zone in self.zones.iter() {
-            if zone.name == name {
-                return Some(zone);
-            }
+        self.zones.iter().find(|zone| zone.name == name)
+    }
+}
+
+/// Settings which control the behavior of `sync --daemon`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// The number of consecutive failed polls after which a watcher is considered unhealthy
+    pub failure_threshold: u32,
+    /// What to do when a watcher becomes unhealthy
+    pub on_failure: FailureAction,
+    /// The maximum amount of time (in seconds) to back off between polls of a failing watcher
+    pub max_backoff: u32,
+}
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            on_failure: FailureAction::default(),
+            max_backoff: 3600,
+        }
+    }
+}
+impl DaemonConfig {
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff as u64)
+    }
+}
+
+/// Configures commands to run before and after a record is created or updated in Cloudflare
+///
+/// Hooks can be configured globally, per interface, and per record. All hooks which apply to a
+/// record are run, from the most general to the most specific.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookConfig {
+    /// A command to run before a change is made, which vetoes the change if it fails
+    pub pre: Option<String>,
+    /// A command to run after a change is made
+    pub post: Option<String>,
+    /// The amount of time (in seconds) after which a hook is killed, and considered to have failed
+    pub timeout: u32,
+}
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            pre: None,
+            post: None,
+            timeout: 30,
+        }
+    }
+}
+
+/// Settings for the built-in HTTP server, which is only started by `sync --daemon`
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The address on which to listen for requests, the server is disabled if not set
+    pub listen: Option<SocketAddr>,
+}
+
+/// Settings for the lock which prevents multiple instances of `sync` from running at once
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    /// What to do if another instance holds the lock
+    pub on_contention: Contention,
+    /// The maximum amount of time (in seconds) to wait for the lock, when waiting
+    pub timeout: u32,
+}
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            on_contention: Contention::Wait,
+            timeout: 60,
+        }
+    }
+}
+
+/// Settings for the registry which tracks the records owned by this instance, see `crate::registry`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Identifies this instance as the owner of the records it manages, ownership isn't tracked if not set
+    pub owner: Option<String>,
+    /// The prefix of the names of the TXT records which track the owner of each record
+    pub prefix: String,
+}
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        Self {
+            owner: None,
+            prefix: "_cfdns.".to_string(),
+        }
+    }
+}
+
+/// Determines what happens when another instance of `sync` is already running
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Contention {
+    /// Wait for the other instance to finish, failing if it takes too long
+    Wait,
+    /// Do nothing, and exit successfully
+    Skip,
+    /// Exit with an error
+    Fail,
+}
+
+/// Settings for publishing state to an MQTT broker, which is only done by `sync --daemon`
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// The hostname of the broker
+    pub host: String,
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+    /// The prefix of all topics to which state is published
+    #[serde(default = "MqttConfig::default_topic")]
+    pub topic: String,
+    /// Whether or not to publish Home Assistant discovery messages
+    #[serde(default = "MqttConfig::default_discovery")]
+    pub discovery: bool,
+    /// The prefix under which Home Assistant listens for discovery messages
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+impl MqttConfig {
+    fn default_port() -> u16 {
+        1883
+    }
+
+    fn default_client_id() -> String {
+        "cfdns".to_string()
+    }
+
+    fn default_topic() -> String {
+        "cfdns".to_string()
+    }
+
+    fn default_discovery() -> bool {
+        true
+    }
+
+    fn default_discovery_prefix() -> String {
+        "homeassistant".to_string()
+    }
+}
+
+/// Configures a destination for notifications about events, e.g. address changes
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    /// The events to notify about, all events are notified if empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// The minimum amount of time (in seconds) between notifications of the same kind of event,
+    /// about the same record or interface
+    #[serde(default)]
+    pub rate_limit: u32,
+    #[serde(flatten)]
+    pub sink: SinkConfig,
+}
+impl NotificationConfig {
+    /// Returns a name for this notification sink suitable for logging, i.e. without secrets
+    pub fn name(&self) -> String {
+        let host = match &self.sink {
+            SinkConfig::Email(email) => email.host.clone(),
+            sink => sink
+                .url()
+                .and_then(|url| reqwest::Url::parse(url).ok())
+                .and_then(|url| url.host_str().map(|host| host.to_string()))
+                .unwrap_or_default(),
+        };
+        format!("{} ({})", self.sink.kind(), host)
+    }
+}
+
+/// The type-specific configuration for a notification sink
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// POSTs a JSON representation of each event to `url`
+    Webhook { url: String },
+    /// Posts a message to a Slack incoming webhook
+    Slack { url: String },
+    /// Posts a message to a Discord webhook
+    Discord { url: String },
+    /// Publishes a message to an ntfy topic, e.g. `https://ntfy.sh/mytopic`
+    Ntfy {
+        url: String,
+        #[serde(default)]
+        token: Option<Secret>,
+    },
+    /// Publishes a message to a Gotify server, using an application token
+    Gotify { url: String, token: Secret },
+    /// Sends an email via an SMTP relay
+    Email(EmailConfig),
+}
+impl SinkConfig {
+    /// Returns the URL to which notifications are delivered, for HTTP-based sinks
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            Self::Webhook { url }
+            | Self::Slack { url }
+            | Self::Discord { url }
+            | Self::Ntfy { url, .. }
+            | Self::Gotify { url, .. } => Some(url.as_str()),
+            Self::Email(_) => None,
         }
+    }
 
-        None
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Webhook { .. } => "webhook",
+            Self::Slack { .. } => "slack",
+            Self::Discord { .. } => "discord",
+            Self::Ntfy { .. } => "ntfy",
+            Self::Gotify { .. } => "gotify",
+            Self::Email(_) => "email",
+        }
     }
 }
 
+/// Configures delivery of notifications by email
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    /// The hostname of the SMTP relay
+    pub host: String,
+    /// The port of the SMTP relay, if not the default for the chosen TLS mode
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
+    /// The sender address, e.g. `cfdns <cfdns@example.com>`
+    pub from: String,
+    /// The recipient addresses
+    pub to: Vec<String>,
+}
+
+/// Determines how connections to an SMTP relay are secured
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Connect in plaintext, then upgrade the connection using STARTTLS (port 587 by default)
+    #[default]
+    StartTls,
+    /// Connect using TLS from the start (port 465 by default)
+    Tls,
+    /// Never use TLS (port 25 by default), only suitable for relays on a trusted network
+    None,
+}
+
+/// Determines how the daemon reacts to a watcher exceeding the failure threshold
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureAction {
+    /// Mark the watcher (and the daemon) unhealthy, but keep retrying
+    #[default]
+    Unhealthy,
+    /// Terminate the daemon with a non-zero exit status
+    Exit,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ZoneConfig {
     #[serde(default)]
     pub id: Option<Id>,
     pub name: String,
-    pub token: String,
+    /// The API token, given inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+    /// The path of a file containing the API token
+    ///
+    /// Relative paths are resolved against `$CREDENTIALS_DIRECTORY` when it is set, as it is for
+    /// credentials loaded via systemd's `LoadCredential`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    /// The name of an environment variable containing the API token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    /// A command, run via `sh -c`, which prints the API token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_command: Option<String>,
+    /// The email address of the account which `api_key` belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// The legacy Global API Key, used instead of an API token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<Secret>,
+}
+impl ZoneConfig {
+    /// Loads the credentials for this zone from wherever they are configured to come from
+    pub fn credentials(&self) -> anyhow::Result<Credentials> {
+        match (self.email.as_ref(), self.api_key.as_ref()) {
+            (Some(email), Some(key)) => Ok(Credentials::GlobalApiKey {
+                email: email.clone(),
+                key: key.clone(),
+            }),
+            _ => self.token().map(Credentials::Token),
+        }
+    }
+
+    /// Loads the API token for this zone from wherever it is configured to come from
+    fn token(&self) -> anyhow::Result<Secret> {
+        let token = if let Some(token) = self.token.as_ref() {
+            token.expose().to_string()
+        } else if let Some(path) = self.token_file.as_ref() {
+            let path = match env::var_os("CREDENTIALS_DIRECTORY") {
+                Some(dir) if path.is_relative() => PathBuf::from(dir).join(path),
+                _ => path.clone(),
+            };
+            std::fs::read_to_string(&path).with_context(|| {
+                format!(
+                    "Failed to read token for zone '{}' from {}",
+                    &self.name,
+                    path.display()
+                )
+            })?
+        } else if let Some(var) = self.token_env.as_ref() {
+            env::var(var).with_context(|| {
+                format!(
+                    "Failed to read token for zone '{}' from ${}",
+                    &self.name, var
+                )
+            })?
+        } else if let Some(command) = self.token_command.as_ref() {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::null())
+                .stderr(Stdio::inherit())
+                .output()
+                .with_context(|| {
+                    format!("Failed to run token command for zone '{}'", &self.name)
+                })?;
+            if !output.status.success() {
+                bail!(
+                    "Token command for zone '{}' failed with {}",
+                    &self.name,
+                    output.status
+                );
+            }
+            String::from_utf8(output.stdout).with_context(|| {
+                format!(
+                    "Token command for zone '{}' printed invalid UTF-8",
+                    &self.name
+                )
+            })?
+        } else {
+            bail!("Zone '{}' is missing a token", &self.name);
+        };
+
+        // Files and command output usually end with a newline, which isn't part of the token
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("The token for zone '{}' is empty", &self.name);
+        }
+        Ok(Secret::new(token))
+    }
+
+    /// Describes where the credentials for this zone are loaded from
+    pub fn token_source(&self) -> &'static str {
+        if self.api_key.is_some() {
+            "api_key"
+        } else if self.token_file.is_some() {
+            "token_file"
+        } else if self.token_env.is_some() {
+            "token_env"
+        } else if self.token_command.is_some() {
+            "token_command"
+        } else {
+            "token"
+        }
+    }
+
+    /// Returns the number of token sources configured for this zone
+    fn token_sources(&self) -> usize {
+        [
+            self.token.is_some(),
+            self.token_file.is_some(),
+            self.token_env.is_some(),
+            self.token_command.is_some(),
+            self.api_key.is_some(),
+        ]
+        .into_iter()
+        .filter(|set| *set)
+        .count()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -46,6 +442,31 @@ pub struct RecordConfig {
     pub ttl: Ttl,
     #[serde(default)]
     pub proxied: ProxyMode,
+    /// The comment to set on the record, left as-is in Cloudflare if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The tags to set on the record, each of the form `name:value`, left as-is in Cloudflare if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    /// What to do if Cloudflare has more than one record with this name and type
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    #[serde(default)]
+    pub hooks: HookConfig,
+}
+
+/// Determines what happens to a record which has duplicates in Cloudflare, i.e. multiple records
+/// with the same name and type
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Refuse to sync the record, until the duplicates are removed
+    #[default]
+    Error,
+    /// Keep all of the records in sync
+    UpdateAll,
+    /// Keep one of the records in sync, and delete the rest
+    KeepOne,
 }
 
 pub fn read_from_path(path: &Path) -> anyhow::Result<ConfigFile> {
@@ -57,7 +478,24 @@ pub fn read_from_path(path: &Path) -> anyhow::Result<ConfigFile> {
     validate(config)
 }
 
-pub fn read_from_system() -> anyhow::Result<ConfigFile> {
+/// Returns the directory in which runtime state is stored for the current platform
+pub fn data_dir() -> anyhow::Result<PathBuf> {
+    use crate::system::Platform;
+
+    let data_dir = match Platform::detect()? {
+        Platform::UDM | Platform::UDMP => PathBuf::from("/mnt/data/cfdns/var"),
+        Platform::UDMSE | Platform::UDR => PathBuf::from("/data/cfdns/var"),
+        Platform::Other => match dirs::data_dir() {
+            Some(dir) => dir.join("cfdns"),
+            None => env::current_dir().unwrap(),
+        },
+    };
+
+    Ok(data_dir)
+}
+
+/// Returns the path to the config file for the current platform
+pub fn system_path() -> anyhow::Result<PathBuf> {
     use crate::system::Platform;
 
     let config_dir = match Platform::detect()? {
@@ -69,11 +507,47 @@ pub fn read_from_system() -> anyhow::Result<ConfigFile> {
         },
     };
 
-    let config_path = config_dir.join("config.toml");
-    read_from_path(config_path.as_path())
+    Ok(config_dir.join("config.toml"))
 }
 
-fn validate(mut config: ConfigFile) -> anyhow::Result<ConfigFile> {
+fn validate(config: ConfigFile) -> anyhow::Result<ConfigFile> {
+    if config.daemon.failure_threshold == 0 {
+        bail!("The daemon failure threshold must be at least 1");
+    }
+
+    let hooks = std::iter::once(&config.hooks)
+        .chain(config.interfaces.iter().map(|i| &i.hooks))
+        .chain(config.records.iter().map(|r| &r.hooks));
+    for hook in hooks {
+        if hook.timeout == 0 {
+            bail!("Hook timeouts must be at least 1 second");
+        }
+    }
+
+    if let Some(mqtt) = config.mqtt.as_ref() {
+        if mqtt.host.is_empty() {
+            bail!("The MQTT broker host must not be empty");
+        }
+        if mqtt.topic.is_empty() || mqtt.topic.contains(['#', '+']) {
+            bail!("The MQTT topic '{}' is invalid", &mqtt.topic);
+        }
+        if mqtt.username.is_none() && mqtt.password.is_some() {
+            bail!("An MQTT password requires a username");
+        }
+    }
+
+    if let Some(owner) = config.registry.owner.as_ref() {
+        if owner.is_empty() || owner.contains([',', '"', '=']) {
+            bail!("The registry owner '{}' is invalid", owner);
+        }
+        if config.registry.prefix.is_empty() || config.registry.prefix.contains('*') {
+            bail!(
+                "The registry prefix '{}' is invalid",
+                &config.registry.prefix
+            );
+        }
+    }
+
     for (i, interface) in config.interfaces.iter().enumerate() {
         if interface.name.is_empty() {
             bail!("Interface is missing name at index {}", i);
@@ -98,15 +572,62 @@ fn validate(mut config: ConfigFile) -> anyhow::Result<ConfigFile> {
                 &record.name
             );
         }
+
+        if record
+            .tags
+            .iter()
+            .flatten()
+            .any(|tag| tag.trim().is_empty())
+        {
+            bail!("Record '{}' has an empty tag", &record.name);
+        }
     }
 
-    for (i, zone) in config.zones.iter_mut().enumerate() {
+    for (i, notification) in config.notifications.iter().enumerate() {
+        if let Some(Err(e)) = notification.sink.url().map(reqwest::Url::parse) {
+            bail!("Notification at index {} has an invalid url: {}", i, e);
+        }
+        if let SinkConfig::Email(email) = &notification.sink {
+            if email.host.is_empty() {
+                bail!("Email notification at index {} is missing a host", i);
+            }
+            if email.to.is_empty() {
+                bail!("Email notification at index {} has no recipients", i);
+            }
+            if email.username.is_some() != email.password.is_some() {
+                bail!(
+                    "Email notification at index {} must set both username and password, or neither",
+                    i
+                );
+            }
+        }
+    }
+
+    for (i, zone) in config.zones.iter().enumerate() {
         if zone.name.is_empty() {
             bail!("Zone is missing name at index {}", i);
         }
 
-        if zone.token.is_empty() {
-            bail!("Zone '{}' is missing a token", &zone.name);
+        // Tokens are only loaded when needed, but exactly one source must be configured
+        match zone.token_sources() {
+            0 => bail!(
+                "Zone '{}' is missing a token, set one of token, token_file, token_env, token_command or api_key",
+                &zone.name
+            ),
+            1 if zone.token.as_ref().is_some_and(|t| t.is_empty()) => {
+                bail!("Zone '{}' is missing a token", &zone.name)
+            }
+            1 => {}
+            _ => bail!(
+                "Zone '{}' must set only one of token, token_file, token_env, token_command or api_key",
+                &zone.name
+            ),
+        }
+        if zone.api_key.is_some() != zone.email.is_some() {
+            bail!(
+                "Zone '{}' must set both email and api_key to use a Global API Key",
+                &zone.name
+            );
         }
     }
 
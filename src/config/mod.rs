@@ -3,31 +3,30 @@ pub mod file;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::net::IpAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
-use crate::cloudflare::{DnsRecord, Zone};
+use crate::cloudflare::{Cloudflare, Credentials, DnsRecord, Zone};
+use crate::hooks::RecordHooks;
+use crate::registry::Registry;
 use crate::system::{AddressFamily, IfConfig, InterfaceInfo};
 use crate::watcher::Watcher;
 
 use self::file::ConfigFile;
 
-#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(clap::ArgEnum, Default, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum LogLevel {
     Off,
     Error,
+    #[default]
     Warn,
     Info,
     Debug,
     Trace,
 }
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Warn
-    }
-}
 #[allow(clippy::from_over_into)]
 impl Into<log::LevelFilter> for LogLevel {
     fn into(self) -> log::LevelFilter {
@@ -66,6 +65,9 @@ impl Display for Interval {
 pub struct Interface {
     pub name: String,
     pub interval: Interval,
+    /// Hooks which run for changes to any record bound to this interface
+    #[serde(default)]
+    pub hooks: file::HookConfig,
     #[serde(skip)]
     pub info: InterfaceInfo,
 }
@@ -77,6 +79,10 @@ impl Interface {
 }
 
 pub struct Config {
+    /// The path from which this configuration was loaded
+    pub path: PathBuf,
+    /// If set, watchers are only created for the interface with this name
+    pub interface: Option<String>,
     pub ifconfig: IfConfig,
     pub file: ConfigFile,
     pub watchers: Vec<Watcher>,
@@ -84,6 +90,8 @@ pub struct Config {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            path: PathBuf::new(),
+            interface: None,
             ifconfig: IfConfig::new(),
             file: ConfigFile::default(),
             watchers: vec![],
@@ -91,18 +99,38 @@ impl Default for Config {
     }
 }
 impl Config {
-    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
-        self::file::read_from_path(path).and_then(Config::try_from)
+    /// Reads and validates the config file at `path`, and creates its watchers
+    ///
+    /// If `interface` is given, only the watchers for that interface are created, and only the
+    /// zones they use are loaded.
+    pub fn from_path(path: &Path, interface: Option<&str>) -> anyhow::Result<Self> {
+        let file = self::file::read_from_path(path)?;
+        let mut config = Self::load(file, interface)?;
+        config.path = path.to_path_buf();
+        Ok(config)
+    }
+
+    /// Reads and validates the config file at `path`, without creating any watchers
+    ///
+    /// This avoids resolving zones and interfaces, for commands which don't sync anything.
+    pub fn without_watchers(path: &Path) -> anyhow::Result<Self> {
+        let file = self::file::read_from_path(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            ..Default::default()
+        })
     }
 
-    pub fn from_system() -> anyhow::Result<Self> {
-        self::file::read_from_system().and_then(Config::try_from)
+    /// Re-reads and re-validates the config file this configuration was loaded from
+    ///
+    /// The current configuration is left untouched, so that if the new configuration
+    /// is invalid, the caller can continue to use the old one.
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        Self::from_path(self.path.as_path(), self.interface.as_deref())
     }
-}
-impl TryFrom<file::ConfigFile> for Config {
-    type Error = anyhow::Error;
 
-    fn try_from(config: file::ConfigFile) -> Result<Self, Self::Error> {
+    fn load(config: file::ConfigFile, interface: Option<&str>) -> anyhow::Result<Self> {
         let ifconfig = IfConfig::new();
         // For each configured interface, create a watcher that will watch on
         // the configured interval. Each watcher will have one or more zones
@@ -110,39 +138,50 @@ impl TryFrom<file::ConfigFile> for Config {
         // all of the configured DNS records which are bound to an address of
         // the interface being monitored
 
-        // Get all of the unique zones, and load their resource id and metadata
-        let zones = {
-            // Build a unique list of zone names referenced by records
-            let mut zone_names = config
-                .records
-                .iter()
-                .map(|r| r.zone.clone())
-                .collect::<HashSet<_>>();
-            // To account for zones with no records, we add any zones defined in the configuration
-            for zone in config.zones.iter() {
-                zone_names.insert(zone.name.clone());
-            }
+        let interfaces = config
+            .interfaces
+            .iter()
+            .filter(|i| interface.is_none_or(|name| i.name == name))
+            .cloned()
+            .collect::<Vec<_>>();
 
-            let mut zones = HashMap::new();
-            for zone_name in zone_names.drain() {
-                let token = config
-                    .zone(&zone_name)
-                    .map(|z| z.token.as_str())
-                    .ok_or_else(|| anyhow!("Reference to undefined zone '{}'", zone_name))?;
-                let zc = config.zone(&zone_name).unwrap();
-                // If a zone id was provided, we can skip requesting the zone from Cloudflare
-                let zone = if let Some(id) = &zc.id {
-                    Zone::new(id.clone(), zone_name.clone())
-                } else {
-                    Zone::get(&zone_name, token)?
-                };
-                zones.insert(zone_name, (token, zone));
+        // Get all of the unique zones used by records bound to those interfaces, and load their
+        // credentials, resource id and metadata. Zones which aren't used are never loaded, so their
+        // credentials aren't needed. A single client is created per set of credentials, so that all
+        // watchers using the same credentials share a connection pool
+        let mut clients = HashMap::<Credentials, Cloudflare>::new();
+        let mut zones = HashMap::new();
+        let zone_names = config
+            .records
+            .iter()
+            .filter(|r| interfaces.iter().any(|i| i.name == r.interface))
+            .map(|r| r.zone.as_str())
+            .collect::<HashSet<_>>();
+        for zone_name in zone_names {
+            let zc = config
+                .zone(zone_name)
+                .ok_or_else(|| anyhow!("Reference to undefined zone '{}'", zone_name))?;
+            let credentials = zc.credentials()?;
+            if let Credentials::GlobalApiKey { .. } = credentials {
+                warn!(
+                    "Zone '{}' uses a Global API Key, which grants full access to the account, consider using a scoped API token instead",
+                    zone_name
+                );
+            }
+            if !clients.contains_key(&credentials) {
+                clients.insert(credentials.clone(), Cloudflare::new(&credentials)?);
             }
-            zones
-        };
+            // If a zone id was provided, we can skip requesting the zone from Cloudflare
+            let zone = if let Some(id) = &zc.id {
+                Zone::new(id.clone(), zone_name.to_string())
+            } else {
+                Zone::get(zone_name, &clients[&credentials])?
+            };
+            zones.insert(zone_name, (credentials, zone));
+        }
 
         let mut watchers = Vec::<Watcher>::new();
-        for mut interface in config.interfaces.iter().cloned() {
+        for mut interface in interfaces {
             // Get interface info
             let name = interface.name.as_str();
             interface.info = ifconfig
@@ -157,7 +196,7 @@ impl TryFrom<file::ConfigFile> for Config {
                 .collect::<Vec<_>>();
             // We need to uniquify watchers by API token, so while we're looping through zones to add
             // to the watcher, use the token associated with the zone to find the corresponding watcher.
-            let mut watchers_by_token = HashMap::<String, Watcher>::new();
+            let mut watchers_by_token = HashMap::<Credentials, Watcher>::new();
             // Build a set of unique zone names
             let zone_names = records
                 .iter()
@@ -170,7 +209,12 @@ impl TryFrom<file::ConfigFile> for Config {
                 // Fetch the zone details and token
                 let (token, mut zone) = zones.get(zone_name).unwrap().clone();
                 // Construct the expected DNS records for this zone
+                let mut hooks = HashMap::new();
+                let mut duplicates = HashMap::new();
                 for record in records.iter().filter(|r| r.zone == zone_name) {
+                    let levels = [&config.hooks, &interface.hooks, &record.hooks];
+                    hooks.insert((record.name.clone(), record.ty), RecordHooks::new(&levels));
+                    duplicates.insert((record.name.clone(), record.ty), record.duplicates);
                     let address_family = record.ty.try_into().unwrap();
                     zone.records.push(DnsRecord {
                         id: None,
@@ -180,22 +224,36 @@ impl TryFrom<file::ConfigFile> for Config {
                         content: interface.address(address_family).unwrap().into(),
                         proxied: record.proxied,
                         ttl: record.ttl,
+                        comment: record.comment.clone(),
+                        tags: record.tags.clone(),
+                        unmanaged: Default::default(),
                     })
                 }
-                if let Some(watcher) = watchers_by_token.get_mut(token) {
+                if let Some(watcher) = watchers_by_token.get_mut(&token) {
                     watcher.watching.push(zone);
+                    watcher.hooks.extend(hooks);
+                    watcher.duplicates.extend(duplicates);
                 } else {
-                    let mut watcher = Watcher::new(interface.clone(), token.to_string())?;
+                    let mut watcher =
+                        Watcher::new(interface.clone(), token.clone(), clients[&token].clone());
                     watcher.watching.push(zone);
-                    watchers_by_token.insert(token.to_string(), watcher);
+                    watcher.hooks = hooks;
+                    watcher.duplicates = duplicates;
+                    watcher.registry = Registry::new(&config.registry);
+                    watchers_by_token.insert(token, watcher);
                 }
             }
 
             // If there were no records/zones defined for this interface, add a placeholder watcher
             // Such a watcher will not have anything to do, but can be used to show information about
-            // the interface configuration, and in the future could support hot-reloading configuration
+            // the interface configuration, and is replaced once records are added to it on a reload
             if watchers_by_token.is_empty() {
-                watchers.push(Watcher::new(interface.clone(), String::new())?);
+                let client = Cloudflare::new(&Credentials::default())?;
+                watchers.push(Watcher::new(
+                    interface.clone(),
+                    Credentials::default(),
+                    client,
+                ));
             } else {
                 // Append watchers for this interface to the final set
                 for watcher in watchers_by_token.into_values() {
@@ -205,6 +263,8 @@ impl TryFrom<file::ConfigFile> for Config {
         }
 
         Ok(Self {
+            path: PathBuf::new(),
+            interface: interface.map(|name| name.to_string()),
             ifconfig,
             file: config,
             watchers,
@@ -0,0 +1,170 @@
+//! This module implements the control socket, used to interact with a running daemon.
+//!
+//! The daemon listens on a Unix domain socket in the data directory. Each connection carries a
+//! single request, encoded as a line of JSON, and receives a single response in the same form.
+//! Requests which only read the state of the daemon are answered directly by the listener thread,
+//! all others are forwarded to the scheduler loop, which handles them between polls.
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::health::{DaemonState, SharedState};
+
+/// How long to wait for the daemon to handle a request, which may involve polling watchers
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// This enum represents the requests which can be made of a running daemon
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Returns the current state of the daemon
+    Status,
+    /// Polls the matching watchers immediately
+    Trigger {
+        interface: Option<String>,
+        record: Option<String>,
+    },
+    /// Stops syncing a record until it is resumed
+    Pause { record: String },
+    /// Resumes syncing a paused record
+    Resume { record: String },
+    /// Reloads the config file, even if it hasn't changed
+    Reload,
+}
+
+/// This enum represents the response of the daemon to a request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok { message: String },
+    Status { state: Box<DaemonState> },
+    Error { message: String },
+}
+impl Response {
+    pub fn ok<S: Into<String>>(message: S) -> Self {
+        Self::Ok {
+            message: message.into(),
+        }
+    }
+
+    pub fn error<S: Into<String>>(message: S) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+}
+
+/// A request forwarded to the scheduler loop, along with where to send the response
+pub type Command = (Request, Sender<Response>);
+
+/// Returns the path of the control socket
+pub fn path() -> anyhow::Result<PathBuf> {
+    Ok(crate::config::file::data_dir()?.join("control.sock"))
+}
+
+/// Starts listening on the control socket at `path`, handling connections on a background thread
+///
+/// Requests which must be handled by the scheduler loop are delivered via the returned receiver.
+pub fn listen(path: &Path, state: SharedState) -> anyhow::Result<Receiver<Command>> {
+    if path.exists() {
+        // A socket left behind by a daemon which didn't shut down cleanly can be removed, but
+        // one which is still accepting connections belongs to a running daemon
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Unable to listen on {}", path.display()))?;
+    // Only the owner of the daemon may control it
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Listening for control requests on {}", path.display());
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+            // Handle each connection on its own thread, so that slow requests don't block others
+            let state = state.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &state, &sender) {
+                    warn!("Failed to handle control request: {:#}", e);
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+fn handle(stream: UnixStream, state: &SharedState, sender: &Sender<Command>) -> anyhow::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    // Connections which close without sending a request are just checking whether we're running
+    if line.is_empty() {
+        return Ok(());
+    }
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(Request::Status) => {
+            let state = state.read().unwrap().clone();
+            Response::Status {
+                state: Box::new(state),
+            }
+        }
+        Ok(request) => {
+            debug!("Received control request: {:?}", &request);
+            let (reply, response) = mpsc::channel();
+            sender
+                .send((request, reply))
+                .map_err(|_| anyhow!("The scheduler is no longer running"))?;
+            response
+                .recv_timeout(TIMEOUT)
+                .unwrap_or_else(|_| Response::error("Timed out waiting for the daemon to respond"))
+        }
+        Err(e) => Response::error(format!("Invalid request: {}", e)),
+    };
+    send(&stream, &response)
+}
+
+/// Sends `request` to the daemon listening on `path`, and waits for its response
+pub fn request(path: &Path, request: &Request) -> anyhow::Result<Response> {
+    let stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "Unable to connect to the daemon at {}, is it running?",
+            path.display()
+        )
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    send(&stream, request)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).context("Received an invalid response from the daemon")
+}
+
+fn send<T: Serialize>(mut stream: &UnixStream, message: &T) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,631 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::bail;
+use log::{error, info, warn};
+
+use crate::cloudflare;
+use crate::config::file::{DaemonConfig, FailureAction};
+use crate::config::Config;
+use crate::control::{self, Request, Response};
+use crate::health::{DaemonState, HealthStatus, SharedState};
+use crate::http;
+use crate::mqtt::Publisher;
+use crate::notify::{self, Event};
+use crate::preflight;
+use crate::scheduler::{self, TimerWheel};
+use crate::system::IfConfig;
+use crate::systemd::Notifier;
+use crate::time::Timestamp;
+use crate::watcher::{Watcher, WatcherKey};
+
+/// How often the daemon checks the config file for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The daemon drives all configured watchers from a single scheduler loop.
+///
+/// Each watcher is scheduled on a timer wheel according to its configured interval, with some
+/// jitter applied so that watchers don't poll in lockstep. On each tick, all watchers which are
+/// due are polled against a single snapshot of the system network interfaces.
+///
+/// The daemon also watches the config file for changes. When it changes, it is re-read and
+/// validated, and the resulting set of watchers is reconciled against the running set: new
+/// watchers are scheduled, watchers which are no longer configured are removed, and watchers
+/// whose configuration changed are replaced and polled immediately. If the new configuration is
+/// invalid, it is rejected and the daemon continues running with the old configuration.
+///
+/// Each watcher tracks its own health. A watcher which fails to poll is retried with exponential
+/// backoff, and once it reaches the configured failure threshold, it is considered unhealthy. The
+/// health of all watchers is persisted after each round of polls, see `DaemonState`.
+pub struct Daemon<F> {
+    /// Determines whether or not a watcher should be run by this daemon
+    filter: F,
+    settings: DaemonConfig,
+    state: SharedState,
+    state_path: PathBuf,
+    systemd: Notifier,
+    /// Publishes state to an MQTT broker, if configured
+    mqtt: Option<Publisher>,
+    /// The names of records which have been paused via the control socket
+    paused: HashSet<String>,
+    /// Whether or not the initial round of polls has finished
+    started: bool,
+    /// Whether or not readiness has been signaled to systemd
+    ready: bool,
+    /// The modification time of the config file when it was last (re)loaded
+    last_modified: Option<SystemTime>,
+    watchers: HashMap<WatcherKey, Watcher>,
+    wheel: TimerWheel<WatcherKey>,
+}
+impl<F> Daemon<F>
+where
+    F: Fn(&mut Watcher) -> bool,
+{
+    pub fn new(filter: F) -> anyhow::Result<Self> {
+        Ok(Self {
+            filter,
+            settings: DaemonConfig::default(),
+            state: Arc::new(RwLock::new(DaemonState::new(
+                std::iter::empty(),
+                &DaemonConfig::default(),
+            ))),
+            state_path: DaemonState::path()?,
+            systemd: Notifier::from_env(),
+            mqtt: None,
+            paused: HashSet::new(),
+            started: false,
+            ready: false,
+            last_modified: None,
+            watchers: HashMap::new(),
+            wheel: TimerWheel::new(),
+        })
+    }
+
+    /// Runs the daemon indefinitely, using the given configuration as a starting point
+    pub fn run(mut self, mut config: Config) -> anyhow::Result<()> {
+        info!("Starting daemon");
+
+        // The HTTP server is only started once, changes to its configuration require a restart
+        if let Some(listen) = config.file.http.listen {
+            http::spawn(listen, self.state.clone())?;
+        }
+        let commands = control::listen(&control::path()?, self.state.clone())?;
+
+        self.last_modified = modified(&config);
+        let mut last_reload_check = Instant::now();
+
+        // Perform the initial poll of all watchers using the interface snapshot from the config
+        self.settings = config.file.daemon.clone();
+        self.configure_mqtt(&config);
+        self.reconcile(config.watchers.drain(0..));
+        preflight(self.watchers.values(), &config)?;
+        let due = self.watchers.keys().cloned().collect::<Vec<_>>();
+        self.poll(due, &config.ifconfig)?;
+        self.started = true;
+        self.state.write().unwrap().started = true;
+
+        let mut next_tick = Instant::now() + scheduler::RESOLUTION;
+        loop {
+            // Wait for the next tick, handling any control requests which arrive in the meantime
+            while let Some(delay) = next_tick.checked_duration_since(Instant::now()) {
+                match commands.recv_timeout(delay) {
+                    Ok((request, reply)) => {
+                        let response = self.handle(request, &mut config)?;
+                        let _ = reply.send(response);
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        thread::sleep(delay);
+                        break;
+                    }
+                }
+            }
+            self.state.write().unwrap().heartbeat = Timestamp::now();
+            self.systemd.watchdog();
+
+            // If polling took longer than a tick, catch up on any ticks we missed
+            let mut due = Vec::new();
+            let now = Instant::now();
+            while next_tick <= now {
+                due.append(&mut self.wheel.tick());
+                next_tick += scheduler::RESOLUTION;
+            }
+            if !due.is_empty() {
+                let ifconfig = IfConfig::new();
+                self.poll(due, &ifconfig)?;
+            }
+
+            if last_reload_check.elapsed() < RELOAD_CHECK_INTERVAL {
+                continue;
+            }
+            last_reload_check = Instant::now();
+
+            if modified(&config) == self.last_modified {
+                continue;
+            }
+            info!("Detected change to {}, reloading", config.path.display());
+            self.reload(&mut config)?;
+        }
+    }
+
+    /// Handles a request received via the control socket
+    ///
+    /// Returns an error only if the daemon should exit, failures to satisfy the request are
+    /// reported via the response.
+    fn handle(&mut self, request: Request, config: &mut Config) -> anyhow::Result<Response> {
+        info!("Handling control request: {:?}", &request);
+        match request {
+            Request::Status => Ok(Response::Status {
+                state: Box::new(self.state.read().unwrap().clone()),
+            }),
+            Request::Trigger { interface, record } => {
+                let due = self
+                    .watchers
+                    .iter()
+                    .filter(|(_, w)| {
+                        interface
+                            .as_ref()
+                            .map(|i| &w.interface.name == i)
+                            .unwrap_or(true)
+                            && record.as_ref().map(|r| w.syncs(r)).unwrap_or(true)
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                if due.is_empty() {
+                    return Ok(Response::error("No watchers match the given filters"));
+                }
+
+                self.wheel.cancel(|k| due.contains(k));
+                self.poll(due.clone(), &IfConfig::new())?;
+
+                let failures = due
+                    .iter()
+                    .filter_map(|key| self.watchers.get(key))
+                    .filter_map(|w| {
+                        w.health
+                            .last_error
+                            .as_ref()
+                            .filter(|_| w.health.consecutive_failures > 0)
+                            .map(|e| format!("{}: {}", &w.interface.name, e))
+                    })
+                    .collect::<Vec<_>>();
+                if failures.is_empty() {
+                    Ok(Response::ok(format!("Synced {} watcher(s)", due.len())))
+                } else {
+                    Ok(Response::error(failures.join("\n")))
+                }
+            }
+            Request::Pause { record } | Request::Resume { record } if !self.syncs(&record) => Ok(
+                Response::error(format!("No watcher syncs a record named {}", record)),
+            ),
+            Request::Pause { record } => {
+                self.paused.insert(record.clone());
+                self.apply_paused();
+                Ok(Response::ok(format!("Paused syncing of {}", record)))
+            }
+            Request::Resume { record } => {
+                if !self.paused.remove(&record) {
+                    return Ok(Response::error(format!("{} is not paused", record)));
+                }
+                self.apply_paused();
+                Ok(Response::ok(format!("Resumed syncing of {}", record)))
+            }
+            Request::Reload => self.reload(config),
+        }
+    }
+
+    /// Reloads the config file, replacing `config` if the new configuration is valid
+    ///
+    /// Returns an error only if the daemon should exit, an invalid configuration is rejected,
+    /// and reported via the response.
+    fn reload(&mut self, config: &mut Config) -> anyhow::Result<Response> {
+        // Recorded before reading the file, so that a change made while reloading isn't missed.
+        // This also covers reloads requested via the control socket, which would otherwise be
+        // repeated when the change is noticed.
+        self.last_modified = modified(config);
+        let mut reloaded = match config.reload() {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!(
+                    "Rejected invalid configuration, continuing with previous configuration: {:#}",
+                    e
+                );
+                return Ok(Response::error(format!(
+                    "Rejected invalid configuration: {:#}",
+                    e
+                )));
+            }
+        };
+
+        let message = format!(
+            "Loaded {} interfaces, {} zones, and {} records from {}",
+            reloaded.file.interfaces.len(),
+            reloaded.file.zones.len(),
+            reloaded.file.records.len(),
+            reloaded.path.display()
+        );
+        info!("{}", &message);
+        self.settings = reloaded.file.daemon.clone();
+        self.configure_mqtt(&reloaded);
+        if let Err(e) = notify::configure(&reloaded.file.notifications) {
+            error!("Unable to reconfigure notifications: {:#}", e);
+        }
+        let changed = self.reconcile(reloaded.watchers.drain(0..));
+        self.poll(changed, &reloaded.ifconfig)?;
+        *config = reloaded;
+
+        Ok(Response::ok(message))
+    }
+
+    /// Returns true if any watcher syncs a record with the given name
+    fn syncs(&self, record: &str) -> bool {
+        self.watchers.values().any(|w| w.syncs(record))
+    }
+
+    /// Applies the current set of paused records to all watchers, and to the shared state
+    fn apply_paused(&mut self) {
+        for watcher in self.watchers.values_mut() {
+            watcher.paused = self.paused.clone();
+        }
+        let mut paused = self.paused.iter().cloned().collect::<Vec<_>>();
+        paused.sort();
+        self.state.write().unwrap().paused = paused;
+    }
+
+    /// Polls each of the given watchers using `ifconfig`, then schedules their next poll
+    ///
+    /// Returns an error if a watcher has become unhealthy, and the daemon is configured to exit
+    /// when that happens.
+    fn poll(&mut self, due: Vec<WatcherKey>, ifconfig: &IfConfig) -> anyhow::Result<()> {
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let threshold = self.settings.failure_threshold;
+        let mut unhealthy = None;
+        for key in due {
+            // The watcher may have been removed by a reload since it was scheduled
+            let watcher = match self.watchers.get_mut(&key) {
+                Some(watcher) => watcher,
+                None => continue,
+            };
+
+            let now = Timestamp::now();
+            let result = watcher.poll(ifconfig);
+            let retry_after = match result.as_ref().map_err(cloudflare::api_error) {
+                Err(Some(cloudflare::Error::RateLimited { retry_after, .. })) => *retry_after,
+                _ => None,
+            };
+            let name = watcher.interface.name.as_str();
+            match result {
+                Ok(_) => {
+                    let failures = watcher.health.consecutive_failures;
+                    if failures > 0 {
+                        info!(
+                            "{} watcher recovered after {} consecutive failures",
+                            name, failures
+                        );
+                        notify::emit(Event::SyncRecovered {
+                            interface: name.to_string(),
+                            failures,
+                        });
+                    }
+                    watcher.health.succeeded(now);
+                }
+                Err(e) => {
+                    watcher.health.failed(now, &e);
+                    let failures = watcher.health.consecutive_failures;
+                    let status = watcher.health.status(threshold);
+                    match status {
+                        HealthStatus::Unhealthy => {
+                            error!(
+                                "{} watcher is unhealthy, {} consecutive polls have failed: {:#}",
+                                name, failures, e
+                            );
+                            unhealthy.get_or_insert_with(|| name.to_string());
+                        }
+                        _ => warn!(
+                            "{} watcher failed to poll ({} of {} allowed failures): {:#}",
+                            name, failures, threshold, e
+                        ),
+                    }
+                    // Notify when a watcher starts failing, and again if it becomes unhealthy
+                    if failures == 1 || failures == threshold {
+                        notify::emit(Event::SyncFailing {
+                            interface: name.to_string(),
+                            failures,
+                            unhealthy: status == HealthStatus::Unhealthy,
+                            error: format!("{:#}", e),
+                            records: watcher.failing(),
+                        });
+                    }
+                }
+            }
+
+            // If Cloudflare told us when to retry, don't retry any sooner than that
+            let interval = watcher.interface.interval.duration();
+            let backoff = watcher
+                .health
+                .backoff(interval, self.settings.max_backoff())
+                .max(retry_after.unwrap_or_default());
+            let delay = scheduler::jitter(backoff);
+            watcher.health.next_attempt = Some(now + delay);
+            self.wheel.schedule(delay, key);
+            self.systemd.watchdog();
+        }
+
+        let mut state = DaemonState::new(self.watchers.values(), &self.settings);
+        state.started = self.started;
+        if let Err(e) = state.save(self.state_path.as_path()) {
+            warn!("Unable to persist daemon state: {:#}", e);
+        }
+        self.notify_systemd(&state);
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.publish(&state);
+        }
+        *self.state.write().unwrap() = state;
+
+        match unhealthy {
+            Some(name) if self.settings.on_failure == FailureAction::Exit => {
+                bail!(
+                    "{} watcher exceeded the failure threshold of {}",
+                    name,
+                    threshold
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Connects to the configured MQTT broker, reconnecting if its configuration changed
+    fn configure_mqtt(&mut self, config: &Config) {
+        match config.file.mqtt.as_ref() {
+            Some(mqtt) if self.mqtt.as_ref().map(|p| p.is_for(mqtt)).unwrap_or(false) => {}
+            Some(mqtt) => self.mqtt = Some(Publisher::connect(mqtt)),
+            None => self.mqtt = None,
+        }
+    }
+
+    /// Reports the state of the daemon to systemd, signaling readiness once every watcher has synced
+    fn notify_systemd(&mut self, state: &DaemonState) {
+        let count = |status| state.watchers.iter().filter(|w| w.health == status).count();
+        self.systemd.status(&format!(
+            "{} watchers: {} healthy, {} failing, {} unhealthy",
+            state.watchers.len(),
+            count(HealthStatus::Healthy),
+            count(HealthStatus::Failing),
+            count(HealthStatus::Unhealthy),
+        ));
+
+        if !self.ready
+            && state
+                .watchers
+                .iter()
+                .all(|w| w.polls.last_success.is_some())
+        {
+            self.systemd.ready();
+            self.ready = true;
+        }
+    }
+
+    /// Adds, removes, or replaces watchers so that they match `watchers`
+    ///
+    /// Returns the keys of all watchers which were added or replaced, and which have not yet
+    /// been scheduled.
+    fn reconcile<I>(&mut self, watchers: I) -> Vec<WatcherKey>
+    where
+        I: Iterator<Item = Watcher>,
+    {
+        let mut stale = self.watchers.keys().cloned().collect::<Vec<_>>();
+        let mut changed = Vec::new();
+
+        for mut watcher in watchers {
+            if !(self.filter)(&mut watcher) {
+                info!(
+                    "Skipping watcher for {}, no records to sync",
+                    &watcher.interface.name
+                );
+                continue;
+            }
+
+            let key = watcher.key();
+            stale.retain(|k| k != &key);
+            match self.watchers.get(&key) {
+                Some(existing) if existing.fingerprint() == watcher.fingerprint() => continue,
+                Some(existing) => {
+                    info!("Reconfiguring {} watcher", &watcher.interface.name);
+                    watcher.health = existing.health.clone();
+                }
+                None => info!("Starting {} watcher", &watcher.interface.name),
+            }
+            self.wheel.cancel(|k| k == &key);
+            watcher.paused = self.paused.clone();
+            self.watchers.insert(key.clone(), watcher);
+            changed.push(key);
+        }
+
+        for key in stale {
+            info!("Stopping {} watcher", &key.interface);
+            self.wheel.cancel(|k| k == &key);
+            self.watchers.remove(&key);
+        }
+
+        changed
+    }
+}
+
+/// Verifies the credentials for each zone synced by `watchers`, failing if any are invalid or
+/// missing permissions
+///
+/// Zones which aren't synced by any of the watchers aren't checked. Checks which can't be
+/// completed, e.g. because the network isn't up yet, are only logged, since the daemon will keep
+/// retrying anyway.
+fn preflight<'a, I>(watchers: I, config: &Config) -> anyhow::Result<()>
+where
+    I: Iterator<Item = &'a Watcher>,
+{
+    let mut checked = HashSet::new();
+    let mut failures = Vec::new();
+    for watcher in watchers {
+        for zone in watcher.watching.iter() {
+            let zc = match config.file.zone(&zone.name) {
+                Some(zc) if checked.insert(zone.name.as_str()) => zc,
+                _ => continue,
+            };
+            let report = preflight::check(zc, Ok(watcher.credentials.clone()));
+            if report.failed() {
+                error!(
+                    "Credentials for zone {} failed verification: {}",
+                    &report.zone,
+                    report.problems()
+                );
+                failures.push(report.zone);
+            } else if report.inconclusive() {
+                warn!(
+                    "Unable to verify credentials for zone {}: {}",
+                    &report.zone,
+                    report.problems()
+                );
+            } else {
+                info!("Verified credentials for zone {}", &report.zone);
+            }
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "Verification of credentials failed for {}, see `cfdns token verify` for details",
+            failures.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Returns the last modification time of the config file, if available
+fn modified(config: &Config) -> Option<SystemTime> {
+    match std::fs::metadata(config.path.as_path()).and_then(|m| m.modified()) {
+        Ok(modified) => Some(modified),
+        Err(e) => {
+            warn!(
+                "Unable to check {} for changes: {}",
+                config.path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::cloudflare::{Cloudflare, Credentials, DnsRecordType, Zone};
+    use crate::config::file::HookConfig;
+    use crate::hooks::RecordHooks;
+
+    /// Returns a watcher for `interface`, polling every `interval` seconds, which syncs `records`
+    fn watcher(interface: &str, interval: u16, records: &[(&str, u32)]) -> Watcher {
+        let interface =
+            serde_json::from_value(json!({ "name": interface, "interval": interval })).unwrap();
+        let client = Cloudflare::new(&Credentials::default()).unwrap();
+        let mut watcher = Watcher::new(interface, Credentials::default(), client);
+        let mut zone: Zone =
+            serde_json::from_value(json!({ "id": "zone", "name": "example.com" })).unwrap();
+        zone.records = records
+            .iter()
+            .map(|(name, ttl)| {
+                serde_json::from_value(json!({
+                    "zone_id": "zone",
+                    "name": name,
+                    "type": "A",
+                    "content": "1.2.3.4",
+                    "ttl": ttl,
+                }))
+                .unwrap()
+            })
+            .collect();
+        watcher.watching.push(zone);
+        watcher
+    }
+
+    fn interfaces(keys: &[WatcherKey]) -> Vec<&str> {
+        keys.iter().map(|key| key.interface.as_str()).collect()
+    }
+
+    #[test]
+    fn compares_watchers_by_fingerprint() {
+        let foo = || watcher("eth0", 60, &[("foo.example.com", 1)]);
+        assert_eq!(foo().fingerprint(), foo().fingerprint());
+
+        let interval = watcher("eth0", 300, &[("foo.example.com", 1)]);
+        assert_ne!(foo().fingerprint(), interval.fingerprint());
+
+        let ttl = watcher("eth0", 60, &[("foo.example.com", 300)]);
+        assert_ne!(foo().fingerprint(), ttl.fingerprint());
+
+        let records = watcher(
+            "eth0",
+            60,
+            &[("foo.example.com", 1), ("bar.example.com", 1)],
+        );
+        assert_ne!(foo().fingerprint(), records.fingerprint());
+
+        let mut hooks = foo();
+        let config: HookConfig = serde_json::from_value(json!({ "post": "true" })).unwrap();
+        hooks.hooks.insert(
+            ("foo.example.com".to_string(), DnsRecordType::A),
+            RecordHooks::new(&[&config]),
+        );
+        assert_ne!(foo().fingerprint(), hooks.fingerprint());
+    }
+
+    #[test]
+    fn reconciles_new_removed_and_changed_watchers() {
+        let mut daemon = Daemon::new(|_: &mut Watcher| true).unwrap();
+        let initial = vec![
+            watcher("eth0", 60, &[("foo.example.com", 1)]),
+            watcher("eth1", 60, &[("bar.example.com", 1)]),
+        ];
+        let mut changed = daemon.reconcile(initial.into_iter());
+        changed.sort_by(|a, b| a.interface.cmp(&b.interface));
+        assert_eq!(interfaces(&changed), ["eth0", "eth1"]);
+
+        // Reloading the same configuration leaves the running watchers alone
+        let unchanged = vec![
+            watcher("eth0", 60, &[("foo.example.com", 1)]),
+            watcher("eth1", 60, &[("bar.example.com", 1)]),
+        ];
+        assert!(daemon.reconcile(unchanged.into_iter()).is_empty());
+
+        let eth0 = daemon.watchers.keys().find(|k| k.interface == "eth0");
+        let eth0 = eth0.unwrap().clone();
+        daemon
+            .watchers
+            .get_mut(&eth0)
+            .unwrap()
+            .health
+            .consecutive_failures = 3;
+
+        let reloaded = vec![
+            watcher("eth0", 300, &[("foo.example.com", 1)]),
+            watcher("eth2", 60, &[("baz.example.com", 1)]),
+        ];
+        let mut changed = daemon.reconcile(reloaded.into_iter());
+        changed.sort_by(|a, b| a.interface.cmp(&b.interface));
+        assert_eq!(interfaces(&changed), ["eth0", "eth2"]);
+
+        let mut running = daemon.watchers.keys().cloned().collect::<Vec<_>>();
+        running.sort_by(|a, b| a.interface.cmp(&b.interface));
+        assert_eq!(interfaces(&running), ["eth0", "eth2"]);
+
+        // A replaced watcher keeps the health of the one it replaced
+        let eth0 = &daemon.watchers[&eth0];
+        assert_eq!(eth0.interface.interval.duration(), Duration::from_secs(300));
+        assert_eq!(eth0.health.consecutive_failures, 3);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::config::file::DaemonConfig;
+use crate::status::{WatcherReport, WatcherStatus};
+use crate::time::Timestamp;
+use crate::watcher::Watcher;
+
+/// The amount of time after which the daemon is considered stalled if the scheduler hasn't run
+///
+/// This is generous, since a single round of polls may involve many slow requests to Cloudflare.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// This enum represents the overall health of a watcher
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    /// The most recent poll succeeded, or the watcher has not been polled yet
+    Healthy,
+    /// One or more consecutive polls have failed, but the failure threshold hasn't been reached
+    Failing,
+    /// The number of consecutive failed polls has reached the failure threshold
+    Unhealthy,
+}
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Tracks the outcome of polls for a single watcher while running as a daemon
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Health {
+    pub consecutive_failures: u32,
+    pub last_attempt: Option<Timestamp>,
+    pub last_success: Option<Timestamp>,
+    pub last_error: Option<String>,
+    pub next_attempt: Option<Timestamp>,
+}
+impl Health {
+    /// Records a successful poll
+    pub fn succeeded(&mut self, now: Timestamp) {
+        self.consecutive_failures = 0;
+        self.last_attempt = Some(now);
+        self.last_success = Some(now);
+        self.last_error = None;
+    }
+
+    /// Records a failed poll
+    pub fn failed(&mut self, now: Timestamp, error: &anyhow::Error) {
+        self.consecutive_failures += 1;
+        self.last_attempt = Some(now);
+        self.last_error = Some(format!("{:#}", error));
+    }
+
+    pub fn status(&self, threshold: u32) -> HealthStatus {
+        match self.consecutive_failures {
+            0 => HealthStatus::Healthy,
+            n if n < threshold => HealthStatus::Failing,
+            _ => HealthStatus::Unhealthy,
+        }
+    }
+
+    /// Returns how long to wait before the next poll, given the watcher's normal `interval`
+    ///
+    /// The delay doubles with each consecutive failure, up to `max`, but never drops below `interval`.
+    pub fn backoff(&self, interval: Duration, max: Duration) -> Duration {
+        if self.consecutive_failures <= 1 {
+            return interval;
+        }
+        let exponent = (self.consecutive_failures - 1).min(16);
+        interval
+            .saturating_mul(1 << exponent)
+            .min(max)
+            .max(interval)
+    }
+}
+
+/// A snapshot of the health of all watchers in a running daemon
+///
+/// This is persisted to disk by the daemon after each round of polls, so that
+/// other processes (e.g. `cfdns show`) can report on it. It is also shared with
+/// the built-in HTTP server, see `SharedState`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DaemonState {
+    pub pid: u32,
+    pub updated: Timestamp,
+    /// The last time the scheduler loop ran, used to detect a stalled daemon
+    pub heartbeat: Timestamp,
+    pub healthy: bool,
+    /// Whether the initial round of polls has finished, the daemon isn't ready until it has
+    #[serde(default)]
+    pub started: bool,
+    /// The names of records which are not being synced until they are resumed
+    #[serde(default)]
+    pub paused: Vec<String>,
+    pub watchers: Vec<WatcherState>,
+}
+
+/// The daemon state, as shared between the scheduler and other threads
+pub type SharedState = Arc<RwLock<DaemonState>>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatcherState {
+    #[serde(flatten)]
+    pub report: WatcherReport,
+    pub zones: Vec<String>,
+    pub health: HealthStatus,
+    #[serde(flatten)]
+    pub polls: Health,
+}
+impl WatcherState {
+    /// Returns true if this state was recorded for `watcher`
+    pub fn is_for(&self, watcher: &Watcher) -> bool {
+        self.report.interface == watcher.interface.name && self.zones == zone_names(watcher)
+    }
+
+    /// Returns true if this watcher has successfully synced within the last two intervals
+    ///
+    /// Watchers with nothing to sync are always considered ready.
+    pub fn is_ready(&self) -> bool {
+        if self.report.status == WatcherStatus::Disabled {
+            return true;
+        }
+        match self.polls.last_success {
+            Some(last_success) => last_success.elapsed() <= self.report.interval.duration() * 2,
+            None => false,
+        }
+    }
+}
+
+impl DaemonState {
+    pub fn new<'a, I>(watchers: I, config: &DaemonConfig) -> Self
+    where
+        I: Iterator<Item = &'a Watcher>,
+    {
+        let mut paused = BTreeSet::new();
+        let watchers = watchers
+            .inspect(|watcher| paused.extend(watcher.paused.iter().cloned()))
+            .map(|watcher| WatcherState {
+                report: WatcherReport::new(watcher, watcher.statuses.clone()),
+                zones: zone_names(watcher),
+                health: watcher.health.status(config.failure_threshold),
+                polls: watcher.health.clone(),
+            })
+            .collect::<Vec<_>>();
+        let healthy = watchers.iter().all(|w| w.health != HealthStatus::Unhealthy);
+
+        let now = Timestamp::now();
+        Self {
+            pid: std::process::id(),
+            updated: now,
+            heartbeat: now,
+            healthy,
+            started: false,
+            paused: paused.into_iter().collect(),
+            watchers,
+        }
+    }
+
+    /// Returns true if the scheduler loop has run recently, and no watcher is unhealthy
+    pub fn is_live(&self) -> bool {
+        self.healthy && self.heartbeat.elapsed() <= LIVENESS_TIMEOUT
+    }
+
+    /// Returns true if the initial round of polls has finished, and every watcher has recently
+    /// completed a successful sync
+    pub fn is_ready(&self) -> bool {
+        self.started && self.watchers.iter().all(|w| w.is_ready())
+    }
+
+    /// Returns the path at which the daemon state is stored
+    pub fn path() -> anyhow::Result<PathBuf> {
+        Ok(crate::config::file::data_dir()?.join("state.json"))
+    }
+
+    /// Loads the most recently persisted daemon state, if there is one
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read daemon state from {}", path.display()))?;
+        let state = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse daemon state at {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Persists this state to `path`, replacing any previous state
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        // Write to a temporary file first, so readers never observe a partially-written state
+        let tmp = path.with_extension("json.tmp");
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(&tmp, contents)
+            .with_context(|| format!("Failed to write daemon state to {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to write daemon state to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn zone_names(watcher: &Watcher) -> Vec<String> {
+    let mut zones = watcher
+        .watching
+        .iter()
+        .map(|zone| zone.name.clone())
+        .collect::<Vec<_>>();
+    zones.sort();
+    zones
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing(times: u32) -> Health {
+        let mut health = Health::default();
+        for _ in 0..times {
+            health.failed(Timestamp::now(), &anyhow::anyhow!("failed"));
+        }
+        health
+    }
+
+    #[test]
+    fn becomes_unhealthy_at_the_threshold() {
+        assert_eq!(failing(0).status(3), HealthStatus::Healthy);
+        assert_eq!(failing(1).status(3), HealthStatus::Failing);
+        assert_eq!(failing(2).status(3), HealthStatus::Failing);
+        assert_eq!(failing(3).status(3), HealthStatus::Unhealthy);
+        assert_eq!(failing(4).status(3), HealthStatus::Unhealthy);
+    }
+
+    #[test]
+    fn recovers_after_a_success() {
+        let mut health = failing(5);
+        health.succeeded(Timestamp::now());
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_error, None);
+        assert_eq!(health.status(3), HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let interval = Duration::from_secs(60);
+        let max = Duration::from_secs(600);
+        let backoff = |failures| failing(failures).backoff(interval, max).as_secs();
+        assert_eq!(backoff(0), 60);
+        assert_eq!(backoff(1), 60);
+        assert_eq!(backoff(2), 120);
+        assert_eq!(backoff(3), 240);
+        assert_eq!(backoff(4), 480);
+        assert_eq!(backoff(5), 600);
+        assert_eq!(backoff(100), 600);
+    }
+
+    #[test]
+    fn never_backs_off_below_the_interval() {
+        let interval = Duration::from_secs(60);
+        let health = failing(3);
+        assert_eq!(health.backoff(interval, Duration::from_secs(10)), interval);
+    }
+}
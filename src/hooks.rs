@@ -0,0 +1,256 @@
+//! This module implements hooks, user-provided commands which run when a record is changed.
+//!
+//! Hooks are run via `sh -c`, with details of the change provided as environment variables:
+//!
+//! * `CFDNS_HOOK`, either `pre` or `post`
+//! * `CFDNS_ACTION`, either `create` or `update`
+//! * `CFDNS_INTERFACE`, the interface the record is bound to
+//! * `CFDNS_ZONE`, the zone the record belongs to
+//! * `CFDNS_RECORD`, the name of the record
+//! * `CFDNS_TYPE`, the type of the record, e.g. `A`
+//! * `CFDNS_OLD_CONTENT`, the content of the record prior to the change, empty if it is being created
+//! * `CFDNS_NEW_CONTENT`, the content of the record after the change
+//!
+//! Each hook runs in its own process group, so that if it times out, any commands it started are
+//! killed along with it.
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use log::{info, warn};
+
+use crate::cloudflare::{DnsContent, DnsRecordType};
+use crate::config::file::HookConfig;
+
+/// How often a running hook is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A single command to run as a hook
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Hook {
+    command: String,
+    timeout: Duration,
+}
+
+/// The hooks which apply to a single record, in the order in which they run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordHooks {
+    pre: Vec<Hook>,
+    post: Vec<Hook>,
+}
+impl RecordHooks {
+    /// Collects the hooks from each of the given levels of configuration, most general first
+    pub fn new(levels: &[&HookConfig]) -> Self {
+        let mut hooks = Self::default();
+        for config in levels {
+            let timeout = Duration::from_secs(config.timeout as u64);
+            if let Some(command) = config.pre.as_ref() {
+                hooks.pre.push(Hook {
+                    command: command.clone(),
+                    timeout,
+                });
+            }
+            if let Some(command) = config.post.as_ref() {
+                hooks.post.push(Hook {
+                    command: command.clone(),
+                    timeout,
+                });
+            }
+        }
+        hooks
+    }
+
+    /// Runs each pre-change hook in order
+    ///
+    /// If any hook fails, the remaining hooks are skipped, and an error is returned, indicating
+    /// that the change should not be made.
+    pub fn pre(&self, change: &Change) -> anyhow::Result<()> {
+        for hook in self.pre.iter() {
+            run(hook, "pre", change).context("Change vetoed by pre-update hook")?;
+        }
+        Ok(())
+    }
+
+    /// Runs each post-change hook in order
+    ///
+    /// Since the change has already been made, failures are logged, but otherwise ignored.
+    pub fn post(&self, change: &Change) {
+        for hook in self.post.iter() {
+            if let Err(e) = run(hook, "post", change) {
+                warn!("Post-update hook for {} failed: {:#}", change.record, e);
+            }
+        }
+    }
+}
+
+/// Describes a change to a record, as provided to hooks
+pub struct Change {
+    pub action: &'static str,
+    pub interface: String,
+    pub zone: String,
+    pub record: String,
+    pub ty: DnsRecordType,
+    pub old: Option<DnsContent>,
+    pub new: DnsContent,
+}
+
+fn run(hook: &Hook, phase: &'static str, change: &Change) -> anyhow::Result<()> {
+    info!(
+        "Running {}-update hook for {}: {}",
+        phase, change.record, &hook.command
+    );
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .env("CFDNS_HOOK", phase)
+        .env("CFDNS_ACTION", change.action)
+        .env("CFDNS_INTERFACE", &change.interface)
+        .env("CFDNS_ZONE", &change.zone)
+        .env("CFDNS_RECORD", &change.record)
+        .env("CFDNS_TYPE", change.ty.to_string())
+        .env(
+            "CFDNS_OLD_CONTENT",
+            change
+                .old
+                .as_ref()
+                .map(|old| old.to_string())
+                .unwrap_or_default(),
+        )
+        .env("CFDNS_NEW_CONTENT", change.new.to_string())
+        .stdin(Stdio::null())
+        // Run in a new process group, so that anything the hook starts can be killed along with it
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Unable to run hook '{}'", &hook.command))?;
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= hook.timeout {
+            kill(&mut child);
+            bail!(
+                "Hook '{}' timed out after {}s",
+                &hook.command,
+                hook.timeout.as_secs()
+            );
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Hook '{}' failed with {}", &hook.command, status))
+    }
+}
+
+/// Kills a hook, along with any processes it started which are still in its process group
+fn kill(child: &mut Child) {
+    // The hook leads its own process group, so the id of the group is the same as its pid
+    let group = -(child.id() as libc::pid_t);
+    // SAFETY: this only sends a signal, and the group can't have been reused, as the hook hasn't been reaped
+    if unsafe { libc::kill(group, libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn change() -> Change {
+        Change {
+            action: "update",
+            interface: "wan0".to_string(),
+            zone: "example.com".to_string(),
+            record: "foo.example.com".to_string(),
+            ty: DnsRecordType::A,
+            old: Some("1.2.3.4".parse().unwrap()),
+            new: "5.6.7.8".parse().unwrap(),
+        }
+    }
+
+    fn hook(command: &str, timeout: Duration) -> Hook {
+        Hook {
+            command: command.to_string(),
+            timeout,
+        }
+    }
+
+    /// Returns a path in the temp dir for a file written by a hook, which doesn't exist yet
+    fn output(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cfdns-hook-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn failing_pre_hooks_veto_the_change() {
+        let ran = output("veto");
+        let hooks = RecordHooks {
+            pre: vec![
+                hook("exit 1", Duration::from_secs(5)),
+                hook(&format!("touch {}", ran.display()), Duration::from_secs(5)),
+            ],
+            post: Vec::new(),
+        };
+        let error = hooks.pre(&change()).unwrap_err();
+        assert_eq!(format!("{}", error), "Change vetoed by pre-update hook");
+        // The remaining hooks are skipped
+        assert!(!ran.exists());
+
+        let hooks = RecordHooks {
+            pre: vec![hook("true", Duration::from_secs(5))],
+            post: Vec::new(),
+        };
+        assert!(hooks.pre(&change()).is_ok());
+    }
+
+    #[test]
+    fn kills_hooks_and_their_children_after_the_timeout() {
+        let finished = output("timeout");
+        let command = format!("(sleep 1; touch {}) | cat", finished.display());
+        let started = Instant::now();
+        let error = run(
+            &hook(&command, Duration::from_millis(200)),
+            "pre",
+            &change(),
+        )
+        .unwrap_err();
+        assert!(format!("{}", error).contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!finished.exists(), "the hook's children kept running");
+    }
+
+    #[test]
+    fn describes_the_change_in_the_environment() {
+        let env = output("env");
+        let command = format!("env | grep ^CFDNS_ | sort > {}", env.display());
+        run(&hook(&command, Duration::from_secs(5)), "post", &change()).unwrap();
+
+        let contents = std::fs::read_to_string(&env).unwrap();
+        let _ = std::fs::remove_file(&env);
+        assert_eq!(
+            contents.lines().collect::<Vec<_>>(),
+            vec![
+                "CFDNS_ACTION=update",
+                "CFDNS_HOOK=post",
+                "CFDNS_INTERFACE=wan0",
+                "CFDNS_NEW_CONTENT=5.6.7.8",
+                "CFDNS_OLD_CONTENT=1.2.3.4",
+                "CFDNS_RECORD=foo.example.com",
+                "CFDNS_TYPE=A",
+                "CFDNS_ZONE=example.com",
+            ]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+use std::thread;
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::health::SharedState;
+use crate::metrics;
+
+/// Starts the built-in HTTP server on `listen`, serving requests on a background thread
+///
+/// The following endpoints are available:
+///
+/// * `GET /metrics`, which renders metrics in the Prometheus text format
+/// * `GET /healthz`, which succeeds if the daemon is running and no watcher is unhealthy
+/// * `GET /readyz`, which succeeds once the initial round of polls has finished, if every watcher
+///   has recently completed a successful sync
+/// * `GET /status`, which renders the status of every watcher and its records as JSON
+pub fn spawn(listen: SocketAddr, state: SharedState) -> anyhow::Result<()> {
+    let server = Server::http(listen)
+        .map_err(|e| anyhow!("Unable to start HTTP server on {}: {}", listen, e))?;
+    info!("Listening for HTTP requests on {}", listen);
+
+    thread::spawn(move || serve(server, state));
+
+    Ok(())
+}
+
+/// Responds to requests received by `server` until it is shut down
+fn serve(server: Server, state: SharedState) {
+    for request in server.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+        if let Err(e) = handle(request, &state) {
+            warn!("Failed to respond to HTTP request: {}", e);
+        }
+    }
+}
+
+fn handle(request: Request, state: &SharedState) -> std::io::Result<()> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    match (request.method(), path) {
+        (Method::Get, "/metrics") => {
+            let response = Response::from_string(metrics::render())
+                .with_header(header("text/plain; version=0.0.4"));
+            request.respond(response)
+        }
+        (Method::Get, "/healthz") => {
+            let live = state.read().unwrap().is_live();
+            request.respond(check(live))
+        }
+        (Method::Get, "/readyz") => {
+            let ready = state.read().unwrap().is_ready();
+            request.respond(check(ready))
+        }
+        (Method::Get, "/status") => {
+            let body = serde_json::to_string_pretty(&*state.read().unwrap())?;
+            let response = Response::from_string(body).with_header(header("application/json"));
+            request.respond(response)
+        }
+        _ => request.respond(Response::from_string("Not Found").with_status_code(404)),
+    }
+}
+
+/// Renders the response for a health check with the given outcome
+fn check(ok: bool) -> Response<std::io::Cursor<Vec<u8>>> {
+    if ok {
+        Response::from_string("OK")
+    } else {
+        Response::from_string("Service Unavailable").with_status_code(503)
+    }
+}
+
+fn header(content_type: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::config::file::DaemonConfig;
+    use crate::health::DaemonState;
+
+    /// Starts a server on a free local port, returning its base URL
+    fn start(state: SharedState) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        thread::spawn(move || serve(server, state));
+        url
+    }
+
+    fn status(url: &str) -> u16 {
+        reqwest::blocking::get(url).unwrap().status().as_u16()
+    }
+
+    #[test]
+    fn is_not_ready_until_the_initial_round_of_polls() {
+        let state = DaemonState::new(std::iter::empty(), &DaemonConfig::default());
+        let state = Arc::new(RwLock::new(state));
+        let url = start(state.clone());
+
+        assert_eq!(status(&format!("{}/readyz", url)), 503);
+        assert_eq!(status(&format!("{}/healthz", url)), 200);
+
+        state.write().unwrap().started = true;
+        assert_eq!(status(&format!("{}/readyz", url)), 200);
+    }
+
+    #[test]
+    fn serves_the_status_and_metrics() {
+        let state = DaemonState::new(std::iter::empty(), &DaemonConfig::default());
+        let url = start(Arc::new(RwLock::new(state)));
+
+        let status = reqwest::blocking::get(format!("{}/status", url))
+            .unwrap()
+            .json::<serde_json::Value>()
+            .unwrap();
+        assert_eq!(status["started"], false);
+        assert_eq!(self::status(&format!("{}/metrics", url)), 200);
+        assert_eq!(self::status(&format!("{}/missing", url)), 404);
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use log::{debug, info};
+
+use crate::config::file::Contention;
+
+/// How often to retry acquiring the lock while waiting for it
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// An advisory lock which ensures only one instance of `sync` is running at a time
+///
+/// The lock is held until this is dropped, or the process exits, whichever comes first. The pid
+/// of the holder is written to the lock file, for diagnostic purposes.
+pub struct InstanceLock {
+    _file: File,
+}
+impl InstanceLock {
+    /// Returns the path of the lock file
+    pub fn path() -> anyhow::Result<PathBuf> {
+        Ok(crate::config::file::data_dir()?.join("cfdns.lock"))
+    }
+
+    /// Acquires the lock at `path`, handling contention as specified
+    ///
+    /// Returns `None` if the lock is held by another instance, and `on_contention` is `Skip`.
+    pub fn acquire(
+        path: &Path,
+        on_contention: Contention,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<Self>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Unable to open lock file {}", path.display()))?;
+
+        let started = Instant::now();
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Unable to lock {}", path.display()));
+                }
+            }
+
+            let holder = holder(&mut file);
+            match on_contention {
+                Contention::Skip => {
+                    info!(
+                        "Another instance of cfdns ({}) is running, skipping",
+                        holder
+                    );
+                    return Ok(None);
+                }
+                Contention::Fail => bail!("Another instance of cfdns ({}) is running", holder),
+                Contention::Wait if started.elapsed() >= timeout => bail!(
+                    "Timed out after {}s waiting for another instance of cfdns ({}) to finish",
+                    timeout.as_secs(),
+                    holder
+                ),
+                Contention::Wait => {
+                    if !waiting {
+                        info!(
+                            "Waiting for another instance of cfdns ({}) to finish",
+                            holder
+                        );
+                        waiting = true;
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+
+        debug!("Acquired lock {}", path.display());
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// Describes the holder of the lock, using the pid it recorded in the lock file
+fn holder(file: &mut File) -> String {
+    let mut pid = String::new();
+    let _ = file.rewind().and_then(|_| file.read_to_string(&mut pid));
+    match pid.trim() {
+        "" => "unknown pid".to_string(),
+        pid => format!("pid {}", pid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a path for a lock file in a fresh temporary directory
+    fn path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cfdns-lock-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("cfdns.lock")
+    }
+
+    fn acquire(path: &Path, on_contention: Contention) -> anyhow::Result<Option<InstanceLock>> {
+        InstanceLock::acquire(path, on_contention, Duration::from_millis(500))
+    }
+
+    #[test]
+    fn records_the_holder() {
+        let path = path("holder");
+        let lock = acquire(&path, Contention::Fail).unwrap();
+        assert!(lock.is_some());
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid, std::process::id().to_string());
+    }
+
+    #[test]
+    fn skips_or_fails_while_held() {
+        let path = path("contention");
+        let _lock = acquire(&path, Contention::Fail).unwrap().unwrap();
+
+        assert!(acquire(&path, Contention::Skip).unwrap().is_none());
+        let error = acquire(&path, Contention::Fail).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Another instance of cfdns (pid {}) is running",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn waits_until_released() {
+        let path = path("wait");
+        let lock = acquire(&path, Contention::Fail).unwrap().unwrap();
+
+        let error = acquire(&path, Contention::Wait).err().unwrap();
+        assert!(error.to_string().starts_with("Timed out"));
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+        assert!(acquire(&path, Contention::Wait).unwrap().is_some());
+        release.join().unwrap();
+    }
+}
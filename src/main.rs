@@ -1,7 +1,26 @@
+pub(crate) mod audit;
 pub(crate) mod cloudflare;
 pub(crate) mod command;
 pub(crate) mod config;
+pub(crate) mod control;
+pub(crate) mod daemon;
+pub(crate) mod health;
+pub(crate) mod hooks;
+pub(crate) mod http;
+pub(crate) mod lock;
+pub(crate) mod metrics;
+pub(crate) mod mqtt;
+pub(crate) mod notify;
+pub(crate) mod plan;
+pub(crate) mod preflight;
+pub(crate) mod reconcile;
+pub(crate) mod registry;
+pub(crate) mod scheduler;
+pub(crate) mod secret;
+pub(crate) mod status;
 pub(crate) mod system;
+pub(crate) mod systemd;
+pub(crate) mod time;
 pub(crate) mod watcher;
 
 use std::path::PathBuf;
@@ -36,9 +55,14 @@ fn main() -> anyhow::Result<()> {
     let mut builder = env_logger::Builder::new();
     builder.filter_level(app.log.into()).parse_env("LOG").init();
 
-    let mut config = match app.config {
-        Some(path) => Config::from_path(path.as_path())?,
-        None => Config::from_system()?,
+    let path = match app.config {
+        Some(path) => path,
+        None => config::file::system_path()?,
+    };
+    let mut config = if app.command.requires_watchers() {
+        Config::from_path(path.as_path(), app.command.interface())?
+    } else {
+        Config::without_watchers(path.as_path())?
     };
 
     app.command.invoke(&mut config)
@@ -0,0 +1,252 @@
+//! This module implements a minimal metrics registry, rendered in the Prometheus text format.
+//!
+//! Metrics are recorded into a process-wide registry via the free functions in this module, so
+//! that instrumented code doesn't need to thread a handle around. Recording is cheap enough that
+//! it is always enabled, regardless of whether or not the metrics endpoint is being served.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::cloudflare::DnsRecordType;
+use crate::system::AddressFamily;
+use crate::time::Timestamp;
+
+/// The upper bounds (in seconds) of the buckets used for the Cloudflare request latency histogram
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Identifies a single DNS record in metric labels
+type RecordLabels = (String, String, DnsRecordType);
+
+#[derive(Default)]
+struct Registry {
+    /// Keyed by (interface, result)
+    polls: BTreeMap<(String, &'static str), u64>,
+    /// Keyed by (endpoint, status)
+    requests: BTreeMap<(&'static str, String), Histogram>,
+    /// Keyed by (zone, record, type) and action
+    updates: BTreeMap<(RecordLabels, &'static str), u64>,
+    /// Keyed by (zone, record, type)
+    last_success: BTreeMap<RecordLabels, Timestamp>,
+    /// Keyed by (interface, family)
+    addresses: BTreeMap<(String, &'static str), IpAddr>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn with_registry<F>(f: F)
+where
+    F: FnOnce(&mut Registry),
+{
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut registry)
+}
+
+/// Records the outcome of a single poll of the watcher for `interface`
+pub fn poll(interface: &str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    with_registry(|r| *r.polls.entry((interface.to_string(), result)).or_default() += 1);
+}
+
+/// Records a request to the Cloudflare API
+///
+/// The `status` is the HTTP status code of the response, or `None` if no response was received.
+pub fn request(endpoint: &'static str, status: Option<u16>, elapsed: Duration) {
+    let status = status
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "error".to_string());
+    with_registry(|r| {
+        r.requests
+            .entry((endpoint, status))
+            .or_default()
+            .observe(elapsed.as_secs_f64())
+    });
+}
+
+/// Records that a DNS record was created or updated in Cloudflare
+pub fn record_updated(zone: &str, name: &str, ty: DnsRecordType, action: &'static str) {
+    let labels = (zone.to_string(), name.to_string(), ty);
+    with_registry(|r| *r.updates.entry((labels, action)).or_default() += 1);
+}
+
+/// Records that a DNS record was successfully synced, whether or not it required changes
+pub fn record_synced(zone: &str, name: &str, ty: DnsRecordType) {
+    let labels = (zone.to_string(), name.to_string(), ty);
+    with_registry(|r| {
+        r.last_success.insert(labels, Timestamp::now());
+    });
+}
+
+/// Records the address currently bound to `interface`, which is what gets published to Cloudflare
+pub fn address(interface: &str, family: AddressFamily, address: Option<IpAddr>) {
+    let family = match family {
+        AddressFamily::IPv4 => "ipv4",
+        AddressFamily::IPv6 => "ipv6",
+        _ => return,
+    };
+    let key = (interface.to_string(), family);
+    with_registry(|r| match address {
+        Some(addr) => {
+            r.addresses.insert(key, addr);
+        }
+        None => {
+            r.addresses.remove(&key);
+        }
+    });
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "cfdns_polls_total",
+        "counter",
+        "Number of watcher polls",
+    );
+    for ((interface, result), count) in registry.polls.iter() {
+        let _ = writeln!(
+            out,
+            "cfdns_polls_total{{interface=\"{}\",result=\"{}\"}} {}",
+            escape(interface),
+            result,
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "cfdns_cloudflare_requests_total",
+        "counter",
+        "Number of requests made to the Cloudflare API",
+    );
+    for ((endpoint, status), histogram) in registry.requests.iter() {
+        let _ = writeln!(
+            out,
+            "cfdns_cloudflare_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+            endpoint, status, histogram.count
+        );
+    }
+
+    header(
+        &mut out,
+        "cfdns_cloudflare_request_duration_seconds",
+        "histogram",
+        "Latency of requests made to the Cloudflare API",
+    );
+    for ((endpoint, status), histogram) in registry.requests.iter() {
+        let labels = format!("endpoint=\"{}\",status=\"{}\"", endpoint, status);
+        for (count, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "cfdns_cloudflare_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                &labels, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "cfdns_cloudflare_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            &labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "cfdns_cloudflare_request_duration_seconds_sum{{{}}} {}",
+            &labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "cfdns_cloudflare_request_duration_seconds_count{{{}}} {}",
+            &labels, histogram.count
+        );
+    }
+
+    header(
+        &mut out,
+        "cfdns_record_updates_total",
+        "counter",
+        "Number of DNS records created or updated in Cloudflare",
+    );
+    for (((zone, name, ty), action), count) in registry.updates.iter() {
+        let _ = writeln!(
+            out,
+            "cfdns_record_updates_total{{zone=\"{}\",record=\"{}\",type=\"{}\",action=\"{}\"}} {}",
+            escape(zone),
+            escape(name),
+            ty,
+            action,
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "cfdns_record_last_success_timestamp_seconds",
+        "gauge",
+        "Time at which a DNS record was last successfully synced",
+    );
+    for ((zone, name, ty), timestamp) in registry.last_success.iter() {
+        let _ = writeln!(
+            out,
+            "cfdns_record_last_success_timestamp_seconds{{zone=\"{}\",record=\"{}\",type=\"{}\"}} {}",
+            escape(zone),
+            escape(name),
+            ty,
+            timestamp.unix_seconds()
+        );
+    }
+
+    header(
+        &mut out,
+        "cfdns_interface_address_info",
+        "gauge",
+        "The address currently published for an interface",
+    );
+    for ((interface, family), addr) in registry.addresses.iter() {
+        let _ = writeln!(
+            out,
+            "cfdns_interface_address_info{{interface=\"{}\",family=\"{}\",address=\"{}\"}} 1",
+            escape(interface),
+            family,
+            addr
+        );
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+}
+
+/// Escapes a label value per the Prometheus text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
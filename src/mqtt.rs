@@ -0,0 +1,433 @@
+//! This module publishes the state of the daemon to an MQTT broker.
+//!
+//! After each round of polls, the state of each interface and record is published as retained JSON
+//! messages beneath the configured topic:
+//!
+//! * `<topic>/availability`, which is `online` while the daemon is connected, and `offline` otherwise
+//! * `<topic>/<interface>/state`, with the current addresses and health of the interface's watchers
+//! * `<topic>/<interface>/<record>/<type>/state`, with the sync status of a single record
+//!
+//! If enabled, Home Assistant discovery messages are published for each of these, so that the
+//! addresses and the health of each record show up as entities of a single `cfdns` device.
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::config::file::MqttConfig;
+use crate::health::{DaemonState, HealthStatus, WatcherState};
+use crate::status::WatcherStatus;
+
+/// How long to wait before reconnecting after the connection to the broker is lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The number of messages which can be queued for the broker
+///
+/// After a (re)connection, a discovery message for every entity is published at once, along with
+/// the state of every interface and record, so this leaves plenty of room for large configs.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A handle to a connection to an MQTT broker
+///
+/// The connection is driven by a background thread, which reconnects as needed. Publishing never
+/// blocks: if the broker is unavailable for long enough that messages back up, they are dropped,
+/// and the next round of polls will publish fresh state, and any discovery messages which were
+/// dropped, anyway.
+pub struct Publisher {
+    config: MqttConfig,
+    client: Client,
+    /// Set by the connection thread whenever a (re)connection succeeds
+    connected: Arc<AtomicBool>,
+    /// Set when the publisher is dropped, to stop the connection thread
+    stopped: Arc<AtomicBool>,
+    /// The discovery topics which have been published since the last (re)connection
+    announced: HashSet<String>,
+}
+impl Publisher {
+    pub fn connect(config: &MqttConfig) -> Self {
+        let availability = format!("{}/availability", &config.topic);
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = config.username.as_ref() {
+            let password = config
+                .password
+                .as_ref()
+                .map(|p| p.expose())
+                .unwrap_or_default();
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let host = format!("{}:{}", &config.host, config.port);
+        let online = client.clone();
+        let on_connect = connected.clone();
+        let stop = stopped.clone();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker at {}", &host);
+                        let _ = online.try_publish(&availability, QoS::AtLeastOnce, true, "online");
+                        on_connect.store(true, Ordering::Relaxed);
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Lost connection to MQTT broker at {}: {}", &host, e);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+            debug!("Disconnected from MQTT broker at {}", &host);
+        });
+
+        Self {
+            config: config.clone(),
+            client,
+            connected,
+            stopped,
+            announced: HashSet::new(),
+        }
+    }
+
+    /// Returns true if this publisher was created with the given configuration
+    pub fn is_for(&self, config: &MqttConfig) -> bool {
+        &self.config == config
+    }
+
+    /// Publishes the state of every interface and record in `state`
+    pub fn publish(&mut self, state: &DaemonState) {
+        // The broker may not have persisted retained discovery messages, so announce them again
+        if self.connected.swap(false, Ordering::Relaxed) {
+            self.announced.clear();
+        }
+
+        // Multiple watchers may share an interface (one per API token), so report the worst of them
+        let mut interfaces: BTreeMap<&str, Vec<&WatcherState>> = BTreeMap::new();
+        for watcher in state.watchers.iter() {
+            interfaces
+                .entry(watcher.report.interface.as_str())
+                .or_default()
+                .push(watcher);
+        }
+
+        for (interface, watchers) in interfaces {
+            let worst = watchers
+                .iter()
+                .max_by_key(|w| (health_severity(w.health), status_severity(w.report.status)))
+                .unwrap();
+            let topic = format!("{}/{}/state", &self.config.topic, slug(interface));
+            self.announce_interface(interface, &topic);
+            self.send(
+                &topic,
+                json!({
+                    "ipv4": worst.report.ipv4,
+                    "ipv6": worst.report.ipv6,
+                    "status": worst.report.status,
+                    "health": worst.health,
+                    "last_success": worst.polls.last_success,
+                    "last_error": worst.polls.last_error,
+                }),
+            );
+
+            for record in watchers.iter().flat_map(|w| w.report.records.iter()) {
+                let topic = format!(
+                    "{}/{}/{}/{}/state",
+                    &self.config.topic,
+                    slug(interface),
+                    slug(&record.name),
+                    record.ty
+                );
+                let id = format!("{}_{}", slug(&record.name), record.ty);
+                let name = format!("{} ({})", &record.name, record.ty);
+                self.announce_record(&id, &name, &topic);
+                self.send(
+                    &topic,
+                    json!({
+                        "status": record.status.to_string(),
+                        "local": record.local,
+                        "upstream": record.upstream,
+                        "proxied": record.proxied,
+                        "ttl": record.ttl,
+                        "changed": record.changed,
+                    }),
+                );
+            }
+        }
+    }
+
+    fn announce_interface(&mut self, interface: &str, topic: &str) {
+        for (component, id, payload) in interface_entities(interface, topic) {
+            self.announce(component, &id, payload);
+        }
+    }
+
+    fn announce_record(&mut self, id: &str, name: &str, topic: &str) {
+        for (component, id, payload) in record_entities(id, name, topic) {
+            self.announce(component, &id, payload);
+        }
+    }
+
+    /// Publishes a Home Assistant discovery message for an entity, if not already published
+    fn announce(&mut self, component: &str, id: &str, mut payload: Value) {
+        if !self.config.discovery {
+            return;
+        }
+        let node = slug(&self.config.client_id);
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            &self.config.discovery_prefix, component, node, id
+        );
+        if self.announced.contains(&topic) {
+            return;
+        }
+
+        payload["unique_id"] = json!(format!("{}_{}", node, id));
+        payload["object_id"] = json!(format!("{}_{}", node, id));
+        payload["availability_topic"] = json!(format!("{}/availability", &self.config.topic));
+        payload["device"] = json!({
+            "identifiers": [node],
+            "name": "cfdns",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        // Only consider the entity announced once the message is queued, so that it's retried otherwise
+        if self.send(&topic, payload) {
+            self.announced.insert(topic);
+        }
+    }
+
+    /// Queues a retained message for the broker, returning false if it had to be dropped
+    fn send(&self, topic: &str, payload: Value) -> bool {
+        let payload = serde_json::to_vec(&payload).unwrap();
+        match self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+        {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Unable to publish to {}: {}", topic, e);
+                false
+            }
+        }
+    }
+}
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let availability = format!("{}/availability", &self.config.topic);
+        let _ = self
+            .client
+            .try_publish(availability, QoS::AtLeastOnce, true, "offline");
+        let _ = self.client.try_disconnect();
+    }
+}
+
+/// Returns the Home Assistant entities for an interface, as (component, id, payload) tuples
+fn interface_entities(interface: &str, topic: &str) -> Vec<(&'static str, String, Value)> {
+    let id = slug(interface);
+    vec![
+        (
+            "sensor",
+            format!("{}_ipv4", id),
+            json!({
+                "name": format!("{} IPv4 address", interface),
+                "state_topic": topic,
+                "value_template": "{{ value_json.ipv4 }}",
+                "icon": "mdi:ip-network",
+            }),
+        ),
+        (
+            "sensor",
+            format!("{}_ipv6", id),
+            json!({
+                "name": format!("{} IPv6 address", interface),
+                "state_topic": topic,
+                "value_template": "{{ value_json.ipv6 }}",
+                "icon": "mdi:ip-network",
+            }),
+        ),
+        (
+            "binary_sensor",
+            format!("{}_problem", id),
+            json!({
+                "name": format!("{} DNS sync", interface),
+                "device_class": "problem",
+                "state_topic": topic,
+                "value_template": "{{ 'OFF' if value_json.health == 'Healthy' else 'ON' }}",
+                "json_attributes_topic": topic,
+            }),
+        ),
+    ]
+}
+
+/// Returns the Home Assistant entities for a record, as (component, id, payload) tuples
+fn record_entities(id: &str, name: &str, topic: &str) -> Vec<(&'static str, String, Value)> {
+    vec![
+        (
+            "sensor",
+            format!("{}_status", id),
+            json!({
+                "name": format!("{} status", name),
+                "state_topic": topic,
+                "value_template": "{{ value_json.status }}",
+                "json_attributes_topic": topic,
+                "icon": "mdi:dns",
+            }),
+        ),
+        (
+            "sensor",
+            format!("{}_changed", id),
+            json!({
+                "name": format!("{} last changed", name),
+                "device_class": "timestamp",
+                "state_topic": topic,
+                "value_template": "{{ value_json.changed }}",
+            }),
+        ),
+    ]
+}
+
+fn health_severity(health: HealthStatus) -> u8 {
+    match health {
+        HealthStatus::Healthy => 0,
+        HealthStatus::Failing => 1,
+        HealthStatus::Unhealthy => 2,
+    }
+}
+
+fn status_severity(status: WatcherStatus) -> u8 {
+    match status {
+        WatcherStatus::Disabled => 0,
+        WatcherStatus::Synced => 1,
+        WatcherStatus::OutOfSync => 2,
+        WatcherStatus::Failed => 3,
+    }
+}
+
+/// Converts `name` into a form suitable for use in topics and entity identifiers
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::Connection;
+
+    use super::*;
+
+    /// Returns a publisher whose queue holds `capacity` messages, and is never drained
+    fn publisher(capacity: usize) -> (Publisher, Connection) {
+        let config: MqttConfig = toml::from_str("host = \"localhost\"").unwrap();
+        let options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        let (client, connection) = Client::new(options, capacity);
+        let publisher = Publisher {
+            config,
+            client,
+            connected: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(true)),
+            announced: HashSet::new(),
+        };
+        (publisher, connection)
+    }
+
+    #[test]
+    fn slugs_names() {
+        assert_eq!(slug("eth8"), "eth8");
+        assert_eq!(slug("wan-1"), "wan-1");
+        assert_eq!(slug("foo.example.com"), "foo_example_com");
+        assert_eq!(slug("*.example.com"), "__example_com");
+    }
+
+    #[test]
+    fn describes_interface_entities() {
+        let entities = interface_entities("eth8.100", "cfdns/eth8_100/state");
+        let ids = entities
+            .iter()
+            .map(|(component, id, _)| format!("{}/{}", component, id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "sensor/eth8_100_ipv4",
+                "sensor/eth8_100_ipv6",
+                "binary_sensor/eth8_100_problem"
+            ]
+        );
+        for (_, _, payload) in entities.iter() {
+            assert_eq!(payload["state_topic"], "cfdns/eth8_100/state");
+        }
+        assert_eq!(entities[0].2["name"], "eth8.100 IPv4 address");
+        assert_eq!(entities[2].2["device_class"], "problem");
+    }
+
+    #[test]
+    fn describes_record_entities() {
+        let entities = record_entities(
+            "foo_example_com_A",
+            "foo.example.com (A)",
+            "cfdns/eth8/foo_example_com/A/state",
+        );
+        let ids = entities
+            .iter()
+            .map(|(component, id, _)| format!("{}/{}", component, id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "sensor/foo_example_com_A_status",
+                "sensor/foo_example_com_A_changed"
+            ]
+        );
+        assert_eq!(entities[0].2["name"], "foo.example.com (A) status");
+        assert_eq!(entities[1].2["device_class"], "timestamp");
+    }
+
+    #[test]
+    fn announces_entities_as_part_of_the_device() {
+        let (mut publisher, _connection) = publisher(8);
+        publisher.announce(
+            "sensor",
+            "eth8_ipv4",
+            json!({ "name": "eth8 IPv4 address" }),
+        );
+        assert_eq!(
+            publisher.announced,
+            HashSet::from(["homeassistant/sensor/cfdns/eth8_ipv4/config".to_string()])
+        );
+    }
+
+    #[test]
+    fn retries_announcements_which_were_dropped() {
+        let (mut publisher, _connection) = publisher(1);
+        publisher.announce_interface("eth8", "cfdns/eth8/state");
+        // Only the first message fits in the queue, the others have to be announced again later
+        assert_eq!(
+            publisher.announced,
+            HashSet::from(["homeassistant/sensor/cfdns/eth8_ipv4/config".to_string()])
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use anyhow::Context;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+
+use crate::config::file::{EmailConfig, SmtpTls};
+use crate::time::Timestamp;
+
+use super::{Event, Sink};
+
+/// A notification sink which delivers events by email, via an SMTP relay
+pub struct Email {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+impl Email {
+    pub fn new(config: &EmailConfig) -> anyhow::Result<Self> {
+        let mut builder = match config.tls {
+            SmtpTls::StartTls => SmtpTransport::starttls_relay(&config.host)?,
+            SmtpTls::Tls => SmtpTransport::relay(&config.host)?,
+            SmtpTls::None => SmtpTransport::builder_dangerous(&config.host),
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                password.expose().to_string(),
+            ));
+        }
+        let transport = builder.timeout(Some(Duration::from_secs(10))).build();
+
+        let from = config
+            .from
+            .parse()
+            .with_context(|| format!("Invalid sender address '{}'", &config.from))?;
+        let to = config
+            .to
+            .iter()
+            .map(|to| {
+                to.parse()
+                    .with_context(|| format!("Invalid recipient address '{}'", to))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            transport,
+            from,
+            to,
+        })
+    }
+}
+impl Sink for Email {
+    fn deliver(&self, event: &Event) -> anyhow::Result<()> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(format!("cfdns: {}", event.title()))
+            .header(ContentType::TEXT_PLAIN);
+        for to in self.to.iter() {
+            message = message.to(to.clone());
+        }
+        let message = message.body(body(event))?;
+
+        self.transport
+            .send(&message)
+            .context("Unable to deliver email notification")?;
+        Ok(())
+    }
+}
+
+/// Renders the plain text body of an email for `event`
+fn body(event: &Event) -> String {
+    let mut body = format!("{}.\n\n", event);
+    match event {
+        Event::AddressChanged {
+            interface,
+            old,
+            new,
+            records,
+        } => {
+            let old = old.map(|old| old.to_string());
+            let _ = writeln!(body, "Interface:    {}", interface);
+            let _ = writeln!(body, "Old address:  {}", old.as_deref().unwrap_or("none"));
+            let _ = writeln!(body, "New address:  {}", new);
+            let _ = writeln!(body, "Records:      {}", records.join(", "));
+        }
+        Event::RecordCreated {
+            interface,
+            zone,
+            record,
+            ty,
+            content,
+        } => {
+            let _ = writeln!(body, "Interface:    {}", interface);
+            let _ = writeln!(body, "Zone:         {}", zone);
+            let _ = writeln!(body, "Record:       {} ({})", record, ty);
+            let _ = writeln!(body, "Content:      {}", content);
+        }
+        Event::RecordUpdated {
+            interface,
+            zone,
+            record,
+            ty,
+            old,
+            new,
+        } => {
+            let _ = writeln!(body, "Interface:    {}", interface);
+            let _ = writeln!(body, "Zone:         {}", zone);
+            let _ = writeln!(body, "Record:       {} ({})", record, ty);
+            let _ = writeln!(body, "Old content:  {}", old);
+            let _ = writeln!(body, "New content:  {}", new);
+        }
+        Event::SyncFailing {
+            interface,
+            failures,
+            error,
+            records,
+            ..
+        } => {
+            let _ = writeln!(body, "Interface:    {}", interface);
+            let _ = writeln!(body, "Failures:     {}", failures);
+            let _ = writeln!(body, "Error:        {}", error);
+            let _ = writeln!(body, "Records:      {}", records.join(", "));
+        }
+        Event::SyncRecovered {
+            interface,
+            failures,
+        } => {
+            let _ = writeln!(body, "Interface:    {}", interface);
+            let _ = writeln!(body, "Failures:     {}", failures);
+        }
+    }
+    let _ = writeln!(body, "Time:         {}", Timestamp::now());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write as _};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    /// Accepts a single SMTP session on a local port, returning the port, and the commands and message it received
+    fn smtp_server() -> (u16, JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut reply = |line: &str| writer.write_all(format!("{}\r\n", line).as_bytes());
+            reply("220 localhost ESMTP").unwrap();
+
+            let mut commands = Vec::new();
+            let mut message = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let verb = command.split(' ').next().unwrap_or_default().to_uppercase();
+                commands.push(command);
+                match verb.as_str() {
+                    "EHLO" => reply("250 localhost").unwrap(),
+                    "DATA" => {
+                        reply("354 go ahead").unwrap();
+                        while reader.read_line(&mut line).unwrap() > 0 && line != ".\r\n" {
+                            message.push_str(&line);
+                            line.clear();
+                        }
+                        line.clear();
+                        reply("250 queued").unwrap();
+                    }
+                    "QUIT" => {
+                        reply("221 bye").unwrap();
+                        break;
+                    }
+                    _ => reply("250 ok").unwrap(),
+                }
+            }
+            (commands, message)
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn delivers_to_an_smtp_relay() {
+        let (port, server) = smtp_server();
+        let email = Email::new(&EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "cfdns <cfdns@example.com>".to_string(),
+            to: vec!["admin@example.com".to_string()],
+        })
+        .unwrap();
+        let event = Event::SyncRecovered {
+            interface: "wan0".to_string(),
+            failures: 3,
+        };
+        email.deliver(&event).unwrap();
+
+        let (commands, message) = server.join().unwrap();
+        assert!(commands.contains(&"MAIL FROM:<cfdns@example.com>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<admin@example.com>".to_string()));
+        assert!(message.contains("Subject: cfdns: Sync recovered\r\n"));
+        assert!(message.contains("Sync for wan0 recovered after 3 consecutive failures."));
+    }
+
+    #[test]
+    fn lists_the_records_which_failed_to_sync() {
+        let event = Event::SyncFailing {
+            interface: "wan0".to_string(),
+            failures: 1,
+            unhealthy: false,
+            error: "Unable to reach Cloudflare".to_string(),
+            records: vec![
+                "foo.example.com (A) in example.com".to_string(),
+                "bar.example.org (AAAA) in example.org".to_string(),
+            ],
+        };
+        let body = body(&event);
+        assert!(body.starts_with(
+            "Sync for wan0 is failing after 1 consecutive failures: Unable to reach Cloudflare.\n\n"
+        ));
+        assert!(body.contains(
+            "Records:      foo.example.com (A) in example.com, bar.example.org (AAAA) in example.org\n"
+        ));
+    }
+}
@@ -0,0 +1,404 @@
+//! This module implements notifications about events which occur while syncing records.
+//!
+//! Events are emitted via [`emit`] from wherever they occur, and are delivered to each configured
+//! sink on a background thread, so that slow or unavailable sinks never hold up syncing. Each sink
+//! can filter which events it receives, and can be rate limited.
+mod email;
+mod webhook;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cloudflare::{DnsContent, DnsRecordType};
+use crate::config::file::{NotificationConfig, SinkConfig};
+
+use self::email::Email;
+use self::webhook::Webhook;
+
+/// This enum represents the kinds of events which can trigger notifications
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    AddressChanged,
+    RecordCreated,
+    RecordUpdated,
+    SyncFailing,
+    SyncRecovered,
+}
+
+/// This enum represents an event which may trigger notifications
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The address bound to an interface changed
+    AddressChanged {
+        interface: String,
+        old: Option<IpAddr>,
+        new: IpAddr,
+        /// The records bound to the changed address
+        records: Vec<String>,
+    },
+    /// A record was created in Cloudflare
+    RecordCreated {
+        interface: String,
+        zone: String,
+        record: String,
+        #[serde(rename = "type")]
+        ty: DnsRecordType,
+        content: DnsContent,
+    },
+    /// An existing record was updated in Cloudflare
+    RecordUpdated {
+        interface: String,
+        zone: String,
+        record: String,
+        #[serde(rename = "type")]
+        ty: DnsRecordType,
+        old: DnsContent,
+        new: DnsContent,
+    },
+    /// A watcher has started failing, or has become unhealthy
+    SyncFailing {
+        interface: String,
+        failures: u32,
+        unhealthy: bool,
+        error: String,
+        /// The records which couldn't be synced, along with their zones
+        records: Vec<String>,
+    },
+    /// A watcher which was failing has successfully synced
+    SyncRecovered { interface: String, failures: u32 },
+}
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::AddressChanged { .. } => EventKind::AddressChanged,
+            Self::RecordCreated { .. } => EventKind::RecordCreated,
+            Self::RecordUpdated { .. } => EventKind::RecordUpdated,
+            Self::SyncFailing { .. } => EventKind::SyncFailing,
+            Self::SyncRecovered { .. } => EventKind::SyncRecovered,
+        }
+    }
+
+    /// Identifies what the event is about, i.e. a record, or an interface
+    ///
+    /// Rate limiting applies to each subject separately, so that a change to one record never
+    /// suppresses a notification about another.
+    pub fn subject(&self) -> String {
+        match self {
+            Self::RecordCreated {
+                zone, record, ty, ..
+            }
+            | Self::RecordUpdated {
+                zone, record, ty, ..
+            } => format!("{} ({}) in {}", record, ty, zone),
+            Self::AddressChanged { interface, .. }
+            | Self::SyncFailing { interface, .. }
+            | Self::SyncRecovered { interface, .. } => interface.clone(),
+        }
+    }
+
+    /// A short, human-readable summary of the event
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::AddressChanged { .. } => "Address changed",
+            Self::RecordCreated { .. } => "Record created",
+            Self::RecordUpdated { .. } => "Record updated",
+            Self::SyncFailing {
+                unhealthy: true, ..
+            } => "Sync unhealthy",
+            Self::SyncFailing { .. } => "Sync failing",
+            Self::SyncRecovered { .. } => "Sync recovered",
+        }
+    }
+}
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AddressChanged {
+                interface,
+                old,
+                new,
+                records,
+            } => {
+                match old {
+                    Some(old) => {
+                        write!(f, "{} address changed from {} to {}", interface, old, new)?
+                    }
+                    None => write!(f, "{} address is now {}", interface, new)?,
+                }
+                if records.is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, ", affecting {}", records.join(", "))
+                }
+            }
+            Self::RecordCreated {
+                record,
+                ty,
+                content,
+                ..
+            } => write!(f, "Created {} record for {} with {}", ty, record, content),
+            Self::RecordUpdated {
+                record,
+                ty,
+                old,
+                new,
+                ..
+            } => write!(
+                f,
+                "Updated {} record for {} from {} to {}",
+                ty, record, old, new
+            ),
+            Self::SyncFailing {
+                interface,
+                failures,
+                unhealthy,
+                error,
+                ..
+            } => {
+                let state = if *unhealthy {
+                    "is unhealthy"
+                } else {
+                    "is failing"
+                };
+                write!(
+                    f,
+                    "Sync for {} {} after {} consecutive failures: {}",
+                    interface, state, failures, error
+                )
+            }
+            Self::SyncRecovered {
+                interface,
+                failures,
+            } => write!(
+                f,
+                "Sync for {} recovered after {} consecutive failures",
+                interface, failures
+            ),
+        }
+    }
+}
+
+/// A destination for notifications
+trait Sink: Send {
+    fn deliver(&self, event: &Event) -> anyhow::Result<()>;
+}
+
+/// A sink, along with its event filter and rate limiting state
+struct Subscription {
+    name: String,
+    sink: Box<dyn Sink>,
+    /// The set of events this sink is interested in, or all events if empty
+    events: HashSet<EventKind>,
+    rate_limit: Duration,
+    /// When each kind of event was last sent about each subject, by title, so that a watcher
+    /// becoming unhealthy isn't suppressed by the notification that it started failing
+    last_sent: HashMap<(&'static str, String), Instant>,
+}
+impl Subscription {
+    fn new(config: &NotificationConfig) -> anyhow::Result<Self> {
+        let sink: Box<dyn Sink> = match &config.sink {
+            SinkConfig::Email(email) => Box::new(Email::new(email)?),
+            sink => Box::new(Webhook::new(sink)?),
+        };
+        Ok(Self {
+            name: config.name(),
+            sink,
+            events: config.events.iter().copied().collect(),
+            rate_limit: Duration::from_secs(config.rate_limit as u64),
+            last_sent: HashMap::new(),
+        })
+    }
+
+    fn notify(&mut self, event: &Event) {
+        let kind = event.kind();
+        if !self.events.is_empty() && !self.events.contains(&kind) {
+            return;
+        }
+        let key = (event.title(), event.subject());
+        if let Some(last_sent) = self.last_sent.get(&key) {
+            if last_sent.elapsed() < self.rate_limit {
+                debug!(
+                    "Rate limited {} notification about {} to {}",
+                    key.0, &key.1, &self.name
+                );
+                return;
+            }
+        }
+        self.last_sent.insert(key, Instant::now());
+        if let Err(e) = self.sink.deliver(event) {
+            warn!("Failed to deliver notification to {}: {:#}", &self.name, e);
+        }
+    }
+}
+
+enum Message {
+    Event(Event),
+    Configure(Vec<Subscription>),
+}
+
+struct Dispatcher {
+    sender: Sender<Message>,
+    handle: JoinHandle<()>,
+}
+
+static DISPATCHER: Mutex<Option<Dispatcher>> = Mutex::new(None);
+
+/// Configures the set of sinks to which notifications are delivered
+///
+/// The first call starts the background delivery thread, subsequent calls replace the set of sinks.
+pub fn configure(configs: &[NotificationConfig]) -> anyhow::Result<()> {
+    let subscriptions = configs
+        .iter()
+        .map(Subscription::new)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut dispatcher = DISPATCHER.lock().unwrap();
+    if let Some(dispatcher) = dispatcher.as_ref() {
+        let _ = dispatcher.sender.send(Message::Configure(subscriptions));
+        return Ok(());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let mut subscriptions = subscriptions;
+        for message in receiver {
+            match message {
+                Message::Event(event) => {
+                    for subscription in subscriptions.iter_mut() {
+                        subscription.notify(&event);
+                    }
+                }
+                Message::Configure(replacement) => {
+                    subscriptions = replacement;
+                }
+            }
+        }
+    });
+    *dispatcher = Some(Dispatcher { sender, handle });
+
+    Ok(())
+}
+
+/// Emits an event, delivering it to all interested sinks
+///
+/// If notifications have not been configured, this does nothing.
+pub fn emit(event: Event) {
+    if let Some(dispatcher) = DISPATCHER.lock().unwrap().as_ref() {
+        let _ = dispatcher.sender.send(Message::Event(event));
+    }
+}
+
+/// Waits for all pending notifications to be delivered, then stops the delivery thread
+pub fn shutdown() {
+    if let Some(dispatcher) = DISPATCHER.lock().unwrap().take() {
+        drop(dispatcher.sender);
+        let _ = dispatcher.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::webhook::tests::receiver;
+    use super::*;
+
+    fn updated(record: &str, new: &str) -> Event {
+        Event::RecordUpdated {
+            interface: "wan0".to_string(),
+            zone: "example.com".to_string(),
+            record: record.to_string(),
+            ty: DnsRecordType::A,
+            old: "1.2.3.4".parse().unwrap(),
+            new: new.parse().unwrap(),
+        }
+    }
+
+    fn subscription(url: String, events: Vec<EventKind>) -> Subscription {
+        Subscription::new(&NotificationConfig {
+            events,
+            rate_limit: 3600,
+            sink: SinkConfig::Webhook { url },
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rate_limits_each_record_separately() {
+        let (url, received) = receiver();
+        let mut subscription = subscription(url, Vec::new());
+        subscription.notify(&updated("foo.example.com", "5.6.7.8"));
+        subscription.notify(&updated("foo.example.com", "9.9.9.9"));
+        subscription.notify(&updated("bar.example.com", "5.6.7.8"));
+
+        let records = received
+            .try_iter()
+            .map(|r| r.json()["record"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec!["foo.example.com", "bar.example.com"]);
+    }
+
+    #[test]
+    fn never_rate_limits_becoming_unhealthy() {
+        let failing = |failures, unhealthy| Event::SyncFailing {
+            interface: "wan0".to_string(),
+            failures,
+            unhealthy,
+            error: "failed".to_string(),
+            records: Vec::new(),
+        };
+        let (url, received) = receiver();
+        let mut subscription = subscription(url, Vec::new());
+        subscription.notify(&failing(1, false));
+        subscription.notify(&failing(2, false));
+        subscription.notify(&failing(5, true));
+        subscription.notify(&failing(6, true));
+
+        let failures = received
+            .try_iter()
+            .map(|r| r.json()["failures"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(failures, vec![1, 5]);
+    }
+
+    #[test]
+    fn only_delivers_the_selected_events() {
+        let (url, received) = receiver();
+        let mut subscription = subscription(url, vec![EventKind::SyncRecovered]);
+        subscription.notify(&updated("foo.example.com", "5.6.7.8"));
+        subscription.notify(&Event::SyncRecovered {
+            interface: "wan0".to_string(),
+            failures: 2,
+        });
+
+        let events = received
+            .try_iter()
+            .map(|r| r.json()["event"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec!["sync_recovered"]);
+    }
+
+    #[test]
+    fn subjects_identify_the_record_or_interface() {
+        assert_eq!(
+            updated("foo.example.com", "5.6.7.8").subject(),
+            "foo.example.com (A) in example.com"
+        );
+        let failing = Event::SyncFailing {
+            interface: "wan0".to_string(),
+            failures: 1,
+            unhealthy: false,
+            error: "failed".to_string(),
+            records: Vec::new(),
+        };
+        assert_eq!(failing.subject(), "wan0");
+    }
+}
@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::blocking::{Client, RequestBuilder};
+use serde_json::json;
+
+use crate::config::file::SinkConfig;
+use crate::time::Timestamp;
+
+use super::{Event, Sink};
+
+/// A notification sink which delivers events via HTTP, formatted according to the type of service
+pub struct Webhook {
+    client: Client,
+    sink: SinkConfig,
+}
+impl Webhook {
+    pub fn new(sink: &SinkConfig) -> anyhow::Result<Self> {
+        let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        Ok(Self {
+            client,
+            sink: sink.clone(),
+        })
+    }
+
+    fn request(&self, event: &Event) -> RequestBuilder {
+        let title = format!("cfdns: {}", event.title());
+        let message = event.to_string();
+        match &self.sink {
+            SinkConfig::Webhook { url } => {
+                let mut payload = serde_json::to_value(event).unwrap();
+                payload["message"] = json!(message);
+                payload["timestamp"] = json!(Timestamp::now());
+                self.client.post(url).json(&payload)
+            }
+            SinkConfig::Slack { url } => self
+                .client
+                .post(url)
+                .json(&json!({ "text": format!("*{}*\n{}", title, message) })),
+            SinkConfig::Discord { url } => self
+                .client
+                .post(url)
+                .json(&json!({ "content": format!("**{}**\n{}", title, message) })),
+            SinkConfig::Ntfy { url, token } => {
+                let request = self
+                    .client
+                    .post(url)
+                    .header("Title", title)
+                    .header(
+                        "Priority",
+                        if is_failure(event) { "high" } else { "default" },
+                    )
+                    .body(message);
+                match token {
+                    Some(token) => request.bearer_auth(token.expose()),
+                    None => request,
+                }
+            }
+            SinkConfig::Gotify { url, token } => self
+                .client
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token.expose())
+                .json(&json!({
+                    "title": title,
+                    "message": message,
+                    "priority": if is_failure(event) { 8 } else { 5 },
+                })),
+            SinkConfig::Email(_) => unreachable!("email notifications are not delivered via http"),
+        }
+    }
+}
+impl Sink for Webhook {
+    fn deliver(&self, event: &Event) -> anyhow::Result<()> {
+        self.request(event)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Unable to deliver {} notification", self.sink.kind()))?;
+        Ok(())
+    }
+}
+
+fn is_failure(event: &Event) -> bool {
+    matches!(event, Event::SyncFailing { .. })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    use serde_json::Value;
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::cloudflare::DnsRecordType;
+    use crate::secret::Secret;
+
+    /// A request received by a local HTTP server
+    pub struct Received {
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+    impl Received {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn json(&self) -> Value {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    /// Starts a local HTTP server which accepts every request, returning its URL, and the requests it receives
+    pub fn receiver() -> (String, Receiver<Received>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let headers = request
+                    .headers()
+                    .iter()
+                    .map(|h| (h.field.to_string(), h.value.to_string()))
+                    .collect();
+                // Hand the request over before responding, so it's available once delivery returns
+                let sent = sender.send(Received { headers, body });
+                let _ = request.respond(Response::empty(200));
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    fn updated() -> Event {
+        Event::RecordUpdated {
+            interface: "wan0".to_string(),
+            zone: "example.com".to_string(),
+            record: "foo.example.com".to_string(),
+            ty: DnsRecordType::A,
+            old: "1.2.3.4".parse().unwrap(),
+            new: "5.6.7.8".parse().unwrap(),
+        }
+    }
+
+    fn failing() -> Event {
+        Event::SyncFailing {
+            interface: "wan0".to_string(),
+            failures: 5,
+            unhealthy: true,
+            error: "Unable to reach Cloudflare".to_string(),
+            records: vec!["foo.example.com (A) in example.com".to_string()],
+        }
+    }
+
+    /// Delivers `event` to a sink built by `sink` from the URL of a local server, returning the request it received
+    fn deliver(sink: impl FnOnce(String) -> SinkConfig, event: &Event) -> Received {
+        let (url, received) = receiver();
+        Webhook::new(&sink(url)).unwrap().deliver(event).unwrap();
+        received.try_recv().unwrap()
+    }
+
+    #[test]
+    fn posts_events_as_json() {
+        let received = deliver(|url| SinkConfig::Webhook { url }, &failing());
+        let payload = received.json();
+        assert_eq!(payload["event"], "sync_failing");
+        assert_eq!(payload["interface"], "wan0");
+        assert_eq!(
+            payload["records"],
+            serde_json::json!(["foo.example.com (A) in example.com"])
+        );
+        assert_eq!(
+            payload["message"],
+            "Sync for wan0 is unhealthy after 5 consecutive failures: Unable to reach Cloudflare"
+        );
+        assert!(payload["timestamp"].is_string());
+    }
+
+    #[test]
+    fn posts_messages_to_slack_and_discord() {
+        let received = deliver(|url| SinkConfig::Slack { url }, &updated());
+        assert_eq!(
+            received.json()["text"],
+            "*cfdns: Record updated*\nUpdated A record for foo.example.com from 1.2.3.4 to 5.6.7.8"
+        );
+
+        let received = deliver(|url| SinkConfig::Discord { url }, &updated());
+        assert_eq!(
+            received.json()["content"],
+            "**cfdns: Record updated**\nUpdated A record for foo.example.com from 1.2.3.4 to 5.6.7.8"
+        );
+    }
+
+    #[test]
+    fn publishes_to_ntfy() {
+        let sink = |url| SinkConfig::Ntfy {
+            url,
+            token: Some(Secret::new("token")),
+        };
+        let received = deliver(sink, &failing());
+        assert_eq!(received.header("Title"), Some("cfdns: Sync unhealthy"));
+        assert_eq!(received.header("Priority"), Some("high"));
+        assert_eq!(received.header("Authorization"), Some("Bearer token"));
+        assert!(received.body.starts_with("Sync for wan0 is unhealthy"));
+
+        let received = deliver(|url| SinkConfig::Ntfy { url, token: None }, &updated());
+        assert_eq!(received.header("Priority"), Some("default"));
+        assert_eq!(received.header("Authorization"), None);
+    }
+
+    #[test]
+    fn publishes_to_gotify() {
+        let sink = |url| SinkConfig::Gotify {
+            url,
+            token: Secret::new("token"),
+        };
+        let received = deliver(sink, &failing());
+        assert_eq!(received.header("X-Gotify-Key"), Some("token"));
+        let payload = received.json();
+        assert_eq!(payload["title"], "cfdns: Sync unhealthy");
+        assert_eq!(payload["priority"], 8);
+    }
+
+    #[test]
+    fn fails_if_the_receiver_does_not_accept_the_notification() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+        thread::spawn(move || {
+            if let Ok(request) = server.recv() {
+                let _ = request.respond(Response::empty(500));
+            }
+        });
+        let webhook = Webhook::new(&SinkConfig::Webhook { url }).unwrap();
+        assert!(webhook.deliver(&updated()).is_err());
+    }
+}
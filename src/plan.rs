@@ -0,0 +1,225 @@
+//! This module describes the changes needed to bring records in Cloudflare in sync with the config.
+//!
+//! Each poll first plans the operation for every watched record, by comparing the desired record
+//! against Cloudflare's view of it, and then applies that plan. A dry run stops after planning.
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cloudflare::{DnsContent, DnsRecord, DnsRecordType, Id, ProxyMode, Ttl};
+
+/// This struct represents the planned operation for a single record
+#[derive(Clone, Debug, Serialize)]
+pub struct RecordPlan {
+    pub interface: String,
+    pub zone: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: DnsRecordType,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+/// This enum represents what needs to happen to a record to bring it in sync
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Operation {
+    /// The record doesn't exist in Cloudflare, and will be created
+    Create {
+        content: DnsContent,
+        proxied: ProxyMode,
+        ttl: Ttl,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tags: Option<Vec<String>>,
+    },
+    /// The record exists in Cloudflare, but one or more of its fields differ
+    Update { id: Id, changes: Vec<FieldChange> },
+    /// The record is a duplicate, and will be deleted
+    Delete { id: Id, content: DnsContent },
+    /// The record is already in sync
+    NoOp,
+    /// The record can't be synced right now, e.g. because it is paused
+    Skip { reason: String },
+}
+impl Operation {
+    /// Compares `desired` against the current state of the record in Cloudflare, if it exists
+    pub fn between(desired: &DnsRecord, upstream: Option<&DnsRecord>) -> Self {
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => {
+                return Self::Create {
+                    content: desired.content.clone(),
+                    proxied: desired.proxied,
+                    ttl: desired.ttl,
+                    comment: desired.comment.clone(),
+                    tags: desired.tags.clone(),
+                }
+            }
+        };
+
+        let mut changes = Vec::new();
+        if desired.content != upstream.content {
+            changes.push(FieldChange::new(
+                "content",
+                &upstream.content,
+                &desired.content,
+            ));
+        }
+        if desired.proxied != upstream.proxied {
+            changes.push(FieldChange::new(
+                "proxied",
+                &upstream.proxied,
+                &desired.proxied,
+            ));
+        }
+        // Cloudflare always uses an automatic TTL for proxied records, regardless of what is requested
+        if desired.ttl != upstream.ttl && desired.proxied == ProxyMode::None {
+            changes.push(FieldChange::new("ttl", &upstream.ttl, &desired.ttl));
+        }
+        // The comment and tags are only compared if they're set in the config, otherwise they're left as-is
+        if let Some(comment) = desired.comment.as_ref() {
+            let current = upstream.comment.clone().unwrap_or_default();
+            if *comment != current {
+                changes.push(FieldChange::new("comment", &current, comment));
+            }
+        }
+        if let Some(tags) = desired.tags.as_ref() {
+            let current = upstream.tags.clone().unwrap_or_default();
+            if sorted(tags) != sorted(&current) {
+                changes.push(FieldChange::new("tags", &current, tags));
+            }
+        }
+
+        match upstream.id.clone() {
+            Some(id) if !changes.is_empty() => Self::Update { id, changes },
+            _ => Self::NoOp,
+        }
+    }
+}
+
+/// Returns a sorted copy of `tags`, so that they can be compared regardless of their order
+fn sorted(tags: &[String]) -> Vec<&String> {
+    let mut tags = tags.iter().collect::<Vec<_>>();
+    tags.sort();
+    tags
+}
+
+/// This struct represents the change to a single field of a record
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Value,
+    pub new: Value,
+}
+impl FieldChange {
+    fn new<T: Serialize>(field: &'static str, old: &T, new: &T) -> Self {
+        Self {
+            field,
+            old: serde_json::to_value(old).unwrap_or(Value::Null),
+            new: serde_json::to_value(new).unwrap_or(Value::Null),
+        }
+    }
+}
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, &self.old, &self.new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Returns a record with the given fields, and defaults for the rest
+    fn record(fields: Value) -> DnsRecord {
+        let mut record = json!({
+            "zone_id": "zone",
+            "name": "foo.example.com",
+            "type": "A",
+            "content": "1.2.3.4",
+        });
+        record
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(record).unwrap()
+    }
+
+    fn upstream(mut fields: Value) -> DnsRecord {
+        fields["id"] = json!("record");
+        record(fields)
+    }
+
+    fn changed_fields(operation: Operation) -> Vec<&'static str> {
+        match operation {
+            Operation::Update { changes, .. } => changes.iter().map(|c| c.field).collect(),
+            Operation::NoOp => Vec::new(),
+            operation => panic!("unexpected operation {:?}", operation),
+        }
+    }
+
+    #[test]
+    fn creates_a_missing_record() {
+        let desired = record(json!({ "comment": "managed" }));
+        match Operation::between(&desired, None) {
+            Operation::Create {
+                content, comment, ..
+            } => {
+                assert_eq!(content, desired.content);
+                assert_eq!(comment.as_deref(), Some("managed"));
+            }
+            operation => panic!("unexpected operation {:?}", operation),
+        }
+    }
+
+    #[test]
+    fn does_nothing_if_in_sync() {
+        let desired = record(json!({}));
+        let upstream = upstream(json!({ "comment": "by hand", "tags": ["a:b"] }));
+        assert!(matches!(
+            Operation::between(&desired, Some(&upstream)),
+            Operation::NoOp
+        ));
+    }
+
+    #[test]
+    fn updates_changed_fields() {
+        let desired = record(json!({ "content": "5.6.7.8", "ttl": 300 }));
+        let upstream = upstream(json!({ "ttl": 120 }));
+        let operation = Operation::between(&desired, Some(&upstream));
+        assert!(matches!(&operation, Operation::Update { id, .. } if id.to_string() == "record"));
+        assert_eq!(changed_fields(operation), vec!["content", "ttl"]);
+    }
+
+    #[test]
+    fn ignores_the_ttl_of_proxied_records() {
+        let desired = record(json!({ "proxied": true, "ttl": 300 }));
+        let proxied = upstream(json!({ "proxied": true }));
+        assert!(changed_fields(Operation::between(&desired, Some(&proxied))).is_empty());
+
+        let unproxied = upstream(json!({ "proxied": false, "ttl": 300 }));
+        assert_eq!(
+            changed_fields(Operation::between(&desired, Some(&unproxied))),
+            vec!["proxied"]
+        );
+    }
+
+    #[test]
+    fn compares_comments_and_tags_only_when_managed() {
+        let desired = record(json!({ "comment": "managed", "tags": ["b:2", "a:1"] }));
+
+        let matching = upstream(json!({ "comment": "managed", "tags": ["a:1", "b:2"] }));
+        assert!(changed_fields(Operation::between(&desired, Some(&matching))).is_empty());
+
+        let missing = upstream(json!({}));
+        assert_eq!(
+            changed_fields(Operation::between(&desired, Some(&missing))),
+            vec!["comment", "tags"]
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! This module implements preflight checks of the credentials used for each configured zone.
+//!
+//! For each zone, the API token (or Global API Key) is verified with Cloudflare, and then used to
+//! read the zone, list its DNS records, and (without changing anything) check that it is allowed
+//! to edit them. These are run by `cfdns token verify`, and when the daemon starts, so that a
+//! mistyped or under-scoped token is reported immediately, rather than the first time a record
+//! needs to be updated.
+use std::fmt;
+use std::time::Duration;
+
+use crate::cloudflare::{api_error, Cloudflare, Credentials, Error};
+use crate::config::file::ZoneConfig;
+use crate::time::Timestamp;
+
+/// Tokens which expire within this amount of time are reported, though they are still valid
+const EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// This enum represents the outcome of a single check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed(String),
+    /// The token is invalid, or is missing a required permission
+    Failed(String),
+    /// The check couldn't be completed, e.g. because Cloudflare couldn't be reached
+    Inconclusive(String),
+    /// The check wasn't run, because an earlier check didn't pass
+    Skipped,
+}
+impl Outcome {
+    /// Returns a short label for this outcome
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Passed(_) => "ok",
+            Self::Failed(_) => "failed",
+            Self::Inconclusive(_) => "unknown",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Passed(detail) | Self::Failed(detail) | Self::Inconclusive(detail) => {
+                write!(f, "{}", detail)
+            }
+            Self::Skipped => write!(f, "not checked"),
+        }
+    }
+}
+
+/// The outcome of a single named check
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+/// The outcome of all checks for a single zone
+#[derive(Clone, Debug)]
+pub struct ZoneReport {
+    pub zone: String,
+    /// Describes the kind of credentials used for the zone, and where they were loaded from
+    pub auth: String,
+    pub checks: Vec<Check>,
+}
+impl ZoneReport {
+    /// Returns true if any check failed
+    pub fn failed(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Failed(_)))
+    }
+
+    /// Returns true if any check couldn't be completed
+    pub fn inconclusive(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Inconclusive(_)))
+    }
+
+    /// Describes the checks which didn't pass, for use in log messages
+    pub fn problems(&self) -> String {
+        self.checks
+            .iter()
+            .filter(|c| matches!(c.outcome, Outcome::Failed(_) | Outcome::Inconclusive(_)))
+            .map(|c| format!("{}: {}", c.name, &c.outcome))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The names of the checks which are run for each zone, in the order they are run
+const CHECKS: [&str; 4] = ["auth", "zone", "dns read", "dns edit"];
+
+/// Runs all checks for the zone configured by `config`, using its already loaded `credentials`
+///
+/// Each check only runs if all of the checks before it passed, the rest are skipped.
+pub fn check(config: &ZoneConfig, credentials: anyhow::Result<Credentials>) -> ZoneReport {
+    let client =
+        credentials.and_then(|credentials| Ok((Cloudflare::new(&credentials)?, credentials)));
+    report(config, client)
+}
+
+/// Runs all checks for the zone configured by `config`, using a `client` created with its credentials
+fn report(config: &ZoneConfig, client: anyhow::Result<(Cloudflare, Credentials)>) -> ZoneReport {
+    let mut outcomes = Vec::new();
+    run(config, client, &mut outcomes);
+
+    let checks = CHECKS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| Check {
+            name,
+            outcome: outcomes.get(i).cloned().unwrap_or(Outcome::Skipped),
+        })
+        .collect();
+    ZoneReport {
+        zone: config.name.clone(),
+        auth: match config.token_source() {
+            "api_key" => "Global API Key".to_string(),
+            source => format!("API token, from {}", source),
+        },
+        checks,
+    }
+}
+
+/// Runs each check in turn, recording its outcome, and stopping at the first which doesn't pass
+fn run(
+    config: &ZoneConfig,
+    client: anyhow::Result<(Cloudflare, Credentials)>,
+    outcomes: &mut Vec<Outcome>,
+) -> Option<()> {
+    let (client, credentials) = match client {
+        Ok(client) => client,
+        Err(e) => return record(outcomes, Outcome::Failed(format!("{:#}", e))),
+    };
+    let outcome = match &credentials {
+        Credentials::Token(_) => verify_token(&client),
+        Credentials::GlobalApiKey { email, .. } => verify_key(&client, email),
+    };
+    record(outcomes, outcome)?;
+
+    let result = match config.id.as_ref() {
+        Some(id) => client.zone_by_id(id).map(Some),
+        None => client.zone_by_name(&config.name),
+    };
+    let zone = match result {
+        Ok(Some(zone)) => zone,
+        Ok(None) => {
+            return record(
+                outcomes,
+                Outcome::Failed(
+                    "not found, either it doesn't exist, or the token is missing the Zone:Read permission"
+                        .to_string(),
+                ),
+            )
+        }
+        Err(e) => return record(outcomes, denied(e, "Zone:Read")),
+    };
+    record(outcomes, Outcome::Passed("readable".to_string()))?;
+
+    let outcome = match client.can_read_records(&zone.id) {
+        Ok(true) => Outcome::Passed("records can be listed".to_string()),
+        Ok(false) => missing("DNS:Read"),
+        Err(e) => Outcome::Inconclusive(format!("{:#}", e)),
+    };
+    record(outcomes, outcome)?;
+
+    // Checking this by making a change would risk actually making it, so the permissions Cloudflare
+    // reports for the zone are used instead
+    let outcome = match zone.can_edit_records() {
+        Some(true) => Outcome::Passed("records can be created and updated".to_string()),
+        Some(false) => missing("DNS:Edit"),
+        None => Outcome::Inconclusive(
+            "Cloudflare didn't report the permissions of the token for this zone".to_string(),
+        ),
+    };
+    record(outcomes, outcome)
+}
+
+/// Records `outcome`, returning `None` if it didn't pass, so that later checks are skipped
+fn record(outcomes: &mut Vec<Outcome>, outcome: Outcome) -> Option<()> {
+    let passed = matches!(outcome, Outcome::Passed(_));
+    outcomes.push(outcome);
+    passed.then_some(())
+}
+
+fn verify_token(client: &Cloudflare) -> Outcome {
+    let status = match client.verify_token() {
+        Ok(status) => status,
+        Err(e) => match api_error(&e) {
+            Some(Error::Auth(_) | Error::Validation { .. }) => {
+                return Outcome::Failed("the token is invalid, or has been deleted".to_string())
+            }
+            _ => return Outcome::Inconclusive(format!("{:#}", e)),
+        },
+    };
+
+    let now = Timestamp::now();
+    if status.status != "active" {
+        return Outcome::Failed(format!("the token is {}", &status.status));
+    }
+    if let Some(not_before) = status.not_before.filter(|t| *t > now) {
+        return Outcome::Failed(format!("the token is not valid until {}", not_before));
+    }
+    match status.expires_on {
+        Some(expires) if expires <= now => {
+            Outcome::Failed(format!("the token expired at {}", expires))
+        }
+        Some(expires) if expires <= now + EXPIRY_WARNING => {
+            Outcome::Passed(format!("active, but expires soon, at {}", expires))
+        }
+        Some(expires) => Outcome::Passed(format!("active, expires at {}", expires)),
+        None => Outcome::Passed("active, never expires".to_string()),
+    }
+}
+
+fn verify_key(client: &Cloudflare, email: &str) -> Outcome {
+    match client.verify_key() {
+        Ok(()) => Outcome::Passed(format!(
+            "Global API Key for {}, consider using a scoped API token instead",
+            email
+        )),
+        Err(e) => match api_error(&e) {
+            Some(Error::Auth(_) | Error::Validation { .. }) => {
+                Outcome::Failed("the email address or Global API Key is invalid".to_string())
+            }
+            _ => Outcome::Inconclusive(format!("{:#}", e)),
+        },
+    }
+}
+
+/// Converts a failed request into an outcome, treating authorization failures as a missing permission
+fn denied(error: anyhow::Error, permission: &str) -> Outcome {
+    match api_error(&error) {
+        Some(Error::Auth(_) | Error::NotFound(_)) => missing(permission),
+        _ => Outcome::Inconclusive(format!("{:#}", error)),
+    }
+}
+
+fn missing(permission: &str) -> Outcome {
+    Outcome::Failed(format!(
+        "the token is missing the {} permission for this zone",
+        permission
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::cloudflare::tests::{failure, stand_in, success, Request};
+
+    const ZONE: &str = "023e105f4ecef8ad9ca31a8372d0c353";
+
+    fn config() -> ZoneConfig {
+        serde_json::from_value(json!({ "name": "example.com", "token": "secret" })).unwrap()
+    }
+
+    /// Runs the checks against a stand-in for Cloudflare, which verifies the token with `token`,
+    /// and reports the zone as `zone`
+    fn checks(token: (u16, Value), zone: (u16, Value)) -> (Vec<Outcome>, Receiver<Request>) {
+        let (client, received) = stand_in(move |request| match request.path.as_str() {
+            "/user/tokens/verify" => token.clone(),
+            "/zones" => zone.clone(),
+            path if path == format!("/zones/{}/dns_records", ZONE) => success(json!([])),
+            path => panic!("unexpected request for {}", path),
+        });
+        let report = report(&config(), Ok((client, Credentials::default())));
+        let outcomes = report.checks.into_iter().map(|c| c.outcome).collect();
+        (outcomes, received)
+    }
+
+    fn active(expires_on: Option<Timestamp>, not_before: Option<Timestamp>) -> (u16, Value) {
+        success(json!({
+            "status": "active",
+            "expires_on": expires_on,
+            "not_before": not_before,
+        }))
+    }
+
+    fn zone(permissions: Option<&[&str]>) -> (u16, Value) {
+        success(json!([{ "id": ZONE, "name": "example.com", "permissions": permissions }]))
+    }
+
+    /// Returns the outcome of verifying a token, which Cloudflare reports the status of with `token`
+    fn verify(token: (u16, Value)) -> Outcome {
+        let (outcomes, _) = checks(token, zone(Some(&["#dns_records:edit"])));
+        outcomes[0].clone()
+    }
+
+    #[test]
+    fn passes_with_all_permissions() {
+        let (outcomes, _) = checks(active(None, None), zone(Some(&["#dns_records:edit"])));
+
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Passed("active, never expires".to_string()),
+                Outcome::Passed("readable".to_string()),
+                Outcome::Passed("records can be listed".to_string()),
+                Outcome::Passed("records can be created and updated".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn verifies_when_the_token_is_valid() {
+        let now = Timestamp::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let (earlier, soon, later) = (now - day, now + day, now + 30 * day);
+
+        assert_eq!(
+            verify(active(Some(later), None)),
+            Outcome::Passed(format!("active, expires at {}", later))
+        );
+        assert_eq!(
+            verify(active(Some(soon), None)),
+            Outcome::Passed(format!("active, but expires soon, at {}", soon))
+        );
+        assert_eq!(
+            verify(active(Some(earlier), None)),
+            Outcome::Failed(format!("the token expired at {}", earlier))
+        );
+        assert_eq!(
+            verify(active(None, Some(later))),
+            Outcome::Failed(format!("the token is not valid until {}", later))
+        );
+        assert_eq!(
+            verify(active(None, Some(earlier))),
+            Outcome::Passed("active, never expires".to_string())
+        );
+        assert_eq!(
+            verify(success(json!({ "status": "disabled" }))),
+            Outcome::Failed("the token is disabled".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_the_remaining_checks_after_a_failure() {
+        let (outcomes, received) = checks(failure(401, 1000), zone(None));
+
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Failed("the token is invalid, or has been deleted".to_string()),
+                Outcome::Skipped,
+                Outcome::Skipped,
+                Outcome::Skipped,
+            ]
+        );
+        let paths: Vec<_> = received.try_iter().map(|r| r.path).collect();
+        assert_eq!(paths, ["/user/tokens/verify"]);
+
+        let (outcomes, _) = checks(failure(500, 1000), zone(None));
+        assert!(matches!(outcomes[0], Outcome::Inconclusive(_)));
+        assert_eq!(
+            outcomes[1..],
+            [Outcome::Skipped, Outcome::Skipped, Outcome::Skipped]
+        );
+    }
+
+    #[test]
+    fn reports_missing_permissions() {
+        let missing = |permission| {
+            Outcome::Failed(format!(
+                "the token is missing the {} permission for this zone",
+                permission
+            ))
+        };
+
+        for status in [403, 404] {
+            let (outcomes, _) = checks(active(None, None), failure(status, 9109));
+            assert_eq!(outcomes[1], missing("Zone:Read"));
+            assert_eq!(outcomes[2..], [Outcome::Skipped, Outcome::Skipped]);
+        }
+
+        let (outcomes, _) = checks(active(None, None), success(json!([])));
+        assert!(matches!(&outcomes[1], Outcome::Failed(detail) if detail.starts_with("not found")));
+
+        let (outcomes, _) = checks(active(None, None), zone(Some(&["#zone:read"])));
+        assert_eq!(outcomes[3], missing("DNS:Edit"));
+
+        let (outcomes, _) = checks(active(None, None), zone(None));
+        assert!(matches!(outcomes[3], Outcome::Inconclusive(_)));
+    }
+
+    #[test]
+    fn fails_without_credentials() {
+        let report = report(&config(), Err(anyhow::anyhow!("no token")));
+
+        assert!(report.failed());
+        assert_eq!(
+            report.checks[0].outcome,
+            Outcome::Failed("no token".to_string())
+        );
+        assert!(report.checks[1..]
+            .iter()
+            .all(|c| c.outcome == Outcome::Skipped));
+        assert_eq!(report.problems(), "auth: no token");
+    }
+}
@@ -0,0 +1,316 @@
+//! This module fetches Cloudflare's view of the records watched in a zone, so that they can be
+//! reconciled against the config.
+//!
+//! Rather than looking up each record individually, all of the records in a zone which could match a
+//! watched record are listed at once, and then matched up locally. This keeps the number of requests
+//! made per poll proportional to the number of zones, rather than the number of records.
+//!
+//! Cloudflare allows multiple records with the same name and type, so each watched record may be
+//! matched by several. What happens to such duplicates is decided by the record's `DuplicatePolicy`.
+use std::collections::HashSet;
+
+use crate::cloudflare::{Cloudflare, DnsRecord, DnsRecordType, Id, Zone};
+use crate::config::file::DuplicatePolicy;
+use crate::plan::Operation;
+
+/// Identifies a record in Cloudflare by zone, name and type
+pub type RecordKey = (Id, String, DnsRecordType);
+
+pub fn key(record: &DnsRecord) -> RecordKey {
+    (record.zone_id.clone(), record.name.clone(), record.ty)
+}
+
+/// The error returned when a record has duplicates, and its policy is to refuse to sync it
+#[derive(Debug, thiserror::Error)]
+#[error("there are {count} {ty} records for {name} in Cloudflare, remove the duplicates, or set a `duplicates` policy for the record")]
+pub struct Duplicates {
+    pub name: String,
+    pub ty: DnsRecordType,
+    pub count: usize,
+}
+
+/// Plans the operations needed to sync `desired`, given the `existing` records with the same name and type
+///
+/// Each operation is paired with the existing record it applies to, if any.
+pub fn reconcile<'a>(
+    desired: &DnsRecord,
+    existing: &'a [DnsRecord],
+    policy: DuplicatePolicy,
+) -> Result<Vec<(Option<&'a DnsRecord>, Operation)>, Duplicates> {
+    let keep = match (existing, policy) {
+        ([], _) => return Ok(vec![(None, Operation::between(desired, None))]),
+        ([_], _) | (_, DuplicatePolicy::UpdateAll) => {
+            return Ok(existing
+                .iter()
+                .map(|record| (Some(record), Operation::between(desired, Some(record))))
+                .collect())
+        }
+        (_, DuplicatePolicy::Error) => {
+            return Err(Duplicates {
+                name: desired.name.clone(),
+                ty: desired.ty,
+                count: existing.len(),
+            })
+        }
+        // Prefer to keep a record which already has the right content, to minimize the changes made
+        (_, DuplicatePolicy::KeepOne) => existing
+            .iter()
+            .position(|record| record.content == desired.content)
+            .unwrap_or(0),
+    };
+
+    let mut operations = Vec::new();
+    for (i, record) in existing.iter().enumerate() {
+        let operation = match record.id.clone() {
+            _ if i == keep => Operation::between(desired, Some(record)),
+            Some(id) => Operation::Delete {
+                id,
+                content: record.content.clone(),
+            },
+            None => continue,
+        };
+        operations.push((Some(record), operation));
+    }
+
+    Ok(operations)
+}
+
+/// Lists the records in Cloudflare with the same name as any of the records watched in `zone`
+///
+/// If `by_type` is set, only records which also have the same type as a watched record are listed,
+/// otherwise records of any type are, e.g. so that a CNAME in the way of an A record can be found.
+pub fn list(client: &Cloudflare, zone: &Zone, by_type: bool) -> anyhow::Result<Vec<DnsRecord>> {
+    let filter = Filter::new(zone, by_type);
+    if filter.names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (name, ty) = filter.query();
+    let records = client.list(&zone.id, name, ty)?;
+
+    Ok(records
+        .into_iter()
+        .filter(|record| filter.matches(record))
+        .collect())
+}
+
+/// The names, and optionally types, of the records watched in a zone
+struct Filter<'a> {
+    names: HashSet<&'a str>,
+    types: Option<HashSet<DnsRecordType>>,
+}
+impl<'a> Filter<'a> {
+    fn new(zone: &'a Zone, by_type: bool) -> Self {
+        Self {
+            names: zone
+                .records
+                .iter()
+                .map(|record| record.name.as_str())
+                .collect(),
+            types: by_type.then(|| zone.records.iter().map(|record| record.ty).collect()),
+        }
+    }
+
+    /// Returns the name and type to ask Cloudflare to filter on
+    ///
+    /// Cloudflare can only filter on a single name and type, so each filter is only used when all
+    /// of the watched records share the same value, otherwise records are filtered locally instead.
+    fn query(&self) -> (Option<&'a str>, Option<DnsRecordType>) {
+        let name = single(&self.names).copied();
+        let ty = self.types.as_ref().and_then(single).copied();
+        (name, ty)
+    }
+
+    /// Returns true if `record` could match one of the watched records
+    fn matches(&self, record: &DnsRecord) -> bool {
+        self.names.contains(record.name.as_str())
+            && self
+                .types
+                .as_ref()
+                .is_none_or(|types| types.contains(&record.ty))
+    }
+}
+
+/// Returns the only value in `values`, if there is exactly one
+fn single<T>(values: &HashSet<T>) -> Option<&T> {
+    match values.len() {
+        1 => values.iter().next(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn record(id: Option<&str>, content: &str) -> DnsRecord {
+        serde_json::from_value(json!({
+            "id": id,
+            "zone_id": "zone",
+            "name": "foo.example.com",
+            "type": "A",
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    fn named(name: &str, ty: &str, content: &str) -> DnsRecord {
+        serde_json::from_value(json!({
+            "zone_id": "zone",
+            "name": name,
+            "type": ty,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    fn zone(records: Vec<DnsRecord>) -> Zone {
+        let mut zone: Zone = serde_json::from_value(json!({
+            "id": "zone",
+            "name": "example.com",
+        }))
+        .unwrap();
+        zone.records = records;
+        zone
+    }
+
+    #[test]
+    fn queries_a_single_name_and_type() {
+        let zone = zone(vec![named("foo.example.com", "A", "1.2.3.4")]);
+        let filter = Filter::new(&zone, true);
+        assert_eq!(
+            filter.query(),
+            (Some("foo.example.com"), Some(DnsRecordType::A))
+        );
+        // Records of any type are listed when a conflicting record is being looked for
+        let filter = Filter::new(&zone, false);
+        assert_eq!(filter.query(), (Some("foo.example.com"), None));
+    }
+
+    #[test]
+    fn queries_everything_for_several_names_and_types() {
+        let zone = zone(vec![
+            named("foo.example.com", "A", "1.2.3.4"),
+            named("bar.example.com", "AAAA", "::1"),
+        ]);
+        let filter = Filter::new(&zone, true);
+        assert_eq!(filter.query(), (None, None));
+    }
+
+    #[test]
+    fn matches_watched_names_and_types() {
+        let zone = zone(vec![
+            named("foo.example.com", "A", "1.2.3.4"),
+            named("bar.example.com", "AAAA", "::1"),
+        ]);
+        let by_type = Filter::new(&zone, true);
+        let any_type = Filter::new(&zone, false);
+
+        let watched = named("foo.example.com", "A", "5.6.7.8");
+        assert!(by_type.matches(&watched));
+        assert!(any_type.matches(&watched));
+
+        // Each watched type applies to every watched name
+        let other_type = named("foo.example.com", "AAAA", "::2");
+        assert!(by_type.matches(&other_type));
+        let cname = named("foo.example.com", "CNAME", "elsewhere.example.com");
+        assert!(!by_type.matches(&cname));
+        assert!(any_type.matches(&cname));
+
+        let unwatched = named("baz.example.com", "A", "1.2.3.4");
+        assert!(!by_type.matches(&unwatched));
+        assert!(!any_type.matches(&unwatched));
+    }
+
+    #[test]
+    fn creates_a_missing_record() {
+        let desired = record(None, "1.2.3.4");
+        for policy in [
+            DuplicatePolicy::Error,
+            DuplicatePolicy::UpdateAll,
+            DuplicatePolicy::KeepOne,
+        ] {
+            let operations = reconcile(&desired, &[], policy).unwrap();
+            assert!(matches!(
+                operations.as_slice(),
+                [(None, Operation::Create { .. })]
+            ));
+        }
+    }
+
+    #[test]
+    fn syncs_a_single_record_regardless_of_policy() {
+        let desired = record(None, "1.2.3.4");
+        let existing = [record(Some("a"), "5.6.7.8")];
+        for policy in [
+            DuplicatePolicy::Error,
+            DuplicatePolicy::UpdateAll,
+            DuplicatePolicy::KeepOne,
+        ] {
+            let operations = reconcile(&desired, &existing, policy).unwrap();
+            assert!(matches!(
+                operations.as_slice(),
+                [(Some(_), Operation::Update { .. })]
+            ));
+        }
+    }
+
+    #[test]
+    fn refuses_duplicates_by_default() {
+        let desired = record(None, "1.2.3.4");
+        let existing = [record(Some("a"), "1.2.3.4"), record(Some("b"), "5.6.7.8")];
+        let error = reconcile(&desired, &existing, DuplicatePolicy::Error).unwrap_err();
+        assert_eq!(error.count, 2);
+        assert_eq!(error.name, "foo.example.com");
+    }
+
+    #[test]
+    fn updates_all_duplicates() {
+        let desired = record(None, "1.2.3.4");
+        let existing = [record(Some("a"), "1.2.3.4"), record(Some("b"), "5.6.7.8")];
+        let operations = reconcile(&desired, &existing, DuplicatePolicy::UpdateAll).unwrap();
+        assert!(matches!(
+            operations.as_slice(),
+            [
+                (Some(_), Operation::NoOp),
+                (Some(_), Operation::Update { .. })
+            ]
+        ));
+    }
+
+    #[test]
+    fn keeps_the_duplicate_with_matching_content() {
+        let desired = record(None, "1.2.3.4");
+        let existing = [
+            record(Some("a"), "5.6.7.8"),
+            record(Some("b"), "1.2.3.4"),
+            record(Some("c"), "9.9.9.9"),
+        ];
+        let operations = reconcile(&desired, &existing, DuplicatePolicy::KeepOne).unwrap();
+        let ids = operations
+            .iter()
+            .map(|(record, operation)| {
+                (record.unwrap().id.as_ref().unwrap().to_string(), operation)
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(ids[0], (ref id, Operation::Delete { .. }) if id == "a"));
+        assert!(matches!(ids[1], (ref id, Operation::NoOp) if id == "b"));
+        assert!(matches!(ids[2], (ref id, Operation::Delete { .. }) if id == "c"));
+    }
+
+    #[test]
+    fn keeps_the_first_duplicate_if_none_match() {
+        let desired = record(None, "1.2.3.4");
+        let existing = [record(Some("a"), "5.6.7.8"), record(Some("b"), "9.9.9.9")];
+        let operations = reconcile(&desired, &existing, DuplicatePolicy::KeepOne).unwrap();
+        assert!(matches!(
+            operations.as_slice(),
+            [
+                (Some(_), Operation::Update { .. }),
+                (Some(_), Operation::Delete { .. })
+            ]
+        ));
+    }
+}
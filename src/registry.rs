@@ -0,0 +1,274 @@
+//! This module implements the registry which tracks which records are owned by each instance of cfdns.
+//!
+//! It is modeled on the TXT registry of external-dns: alongside each record it owns, an instance
+//! keeps a TXT record (the marker) which names its owner. Existing records are only updated if they
+//! are owned by this instance, so that records managed by hand, or by another instance, are never
+//! clobbered. Records created by cfdns are owned from the start, others can be taken over with
+//! `cfdns adopt`.
+use anyhow::bail;
+use log::info;
+
+use crate::cloudflare::{Cloudflare, DnsContent, DnsRecord, DnsRecordType, ProxyMode, Ttl};
+use crate::config::file::RegistryConfig;
+
+/// Identifies TXT records as ownership markers written by cfdns
+const HERITAGE: &str = "heritage=cfdns";
+/// Precedes the owner id in the content of a marker
+const OWNER: &str = "cfdns/owner=";
+
+/// This enum represents who owns a record, according to the registry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ownership {
+    /// The record is owned by this instance
+    Owned,
+    /// The record isn't owned by any instance of cfdns
+    Unowned,
+    /// The record is owned by another instance, with the given owner id
+    Foreign(String),
+}
+
+/// The error returned when a change to a record is refused, because this instance doesn't own it
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct NotOwned(pub String);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registry {
+    owner: String,
+    prefix: String,
+}
+impl Registry {
+    /// Returns the registry described by `config`, or `None` if ownership isn't tracked
+    pub fn new(config: &RegistryConfig) -> Option<Self> {
+        config.owner.as_ref().map(|owner| Self {
+            owner: owner.clone(),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    /// The owner id of this instance
+    pub fn owner(&self) -> &str {
+        self.owner.as_str()
+    }
+
+    /// Returns the name of the marker which tracks the owner of `record`
+    ///
+    /// The type is part of the name, so that the A and AAAA records of a name can be owned separately.
+    pub fn marker_name(&self, record: &DnsRecord) -> String {
+        // A wildcard is only valid as the first label of a name, so it can't be prefixed as-is
+        let name = match record.name.strip_prefix("*.") {
+            Some(parent) => format!("_wildcard.{}", parent),
+            None => record.name.clone(),
+        };
+        format!(
+            "{}{}.{}",
+            &self.prefix,
+            record.ty.to_string().to_lowercase(),
+            name
+        )
+    }
+
+    /// Determines who owns `record`, by looking up its marker in Cloudflare
+    pub fn ownership(&self, client: &Cloudflare, record: &DnsRecord) -> anyhow::Result<Ownership> {
+        let markers = self.markers(client, record)?;
+        Ok(self.owner_of(&markers))
+    }
+
+    /// Records this instance as the owner of `record`, creating its marker if needed
+    ///
+    /// A record owned by another instance is only taken over if `force` is set. Returns who owned
+    /// the record beforehand.
+    pub fn claim(
+        &self,
+        client: &Cloudflare,
+        record: &DnsRecord,
+        force: bool,
+    ) -> anyhow::Result<Ownership> {
+        let markers = self.markers(client, record)?;
+        let ownership = self.owner_of(&markers);
+        match &ownership {
+            Ownership::Owned => {}
+            Ownership::Foreign(owner) if !force => {
+                bail!(NotOwned(format!(
+                    "{} ({}) is owned by '{}'",
+                    &record.name, &record.ty, owner
+                )));
+            }
+            Ownership::Foreign(owner) => {
+                info!(
+                    "Taking over {} ({}) from '{}'",
+                    &record.name, &record.ty, owner
+                );
+                let mut marker = markers.into_iter().find(|m| owner_id(m).is_some()).unwrap();
+                marker.content = self.content();
+                client.update(&mut marker)?;
+            }
+            Ownership::Unowned => {
+                info!("Claiming ownership of {} ({})", &record.name, &record.ty);
+                let mut marker = DnsRecord {
+                    id: None,
+                    zone_id: record.zone_id.clone(),
+                    name: self.marker_name(record),
+                    ty: DnsRecordType::TXT,
+                    content: self.content(),
+                    proxied: ProxyMode::None,
+                    ttl: Ttl::default(),
+                    comment: None,
+                    tags: None,
+                    unmanaged: Default::default(),
+                };
+                client.create(&mut marker)?;
+            }
+        }
+
+        Ok(ownership)
+    }
+
+    /// Removes the marker recording this instance as the owner of `record`, if there is one
+    ///
+    /// Used to undo a claim made for a record which then couldn't be created.
+    pub fn release(&self, client: &Cloudflare, record: &DnsRecord) -> anyhow::Result<()> {
+        let markers = self.markers(client, record)?;
+        for marker in markers.iter() {
+            if owner_id(marker).as_ref() == Some(&self.owner) {
+                info!("Releasing ownership of {} ({})", &record.name, &record.ty);
+                client.delete(marker)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the TXT records at the marker name of `record`, which may include some not written by cfdns
+    fn markers(&self, client: &Cloudflare, record: &DnsRecord) -> anyhow::Result<Vec<DnsRecord>> {
+        let name = self.marker_name(record);
+        client.list(&record.zone_id, Some(&name), Some(DnsRecordType::TXT))
+    }
+
+    fn owner_of(&self, markers: &[DnsRecord]) -> Ownership {
+        let owners = markers.iter().filter_map(owner_id).collect::<Vec<_>>();
+        if owners.contains(&self.owner) {
+            Ownership::Owned
+        } else if let Some(owner) = owners.into_iter().next() {
+            Ownership::Foreign(owner)
+        } else {
+            Ownership::Unowned
+        }
+    }
+
+    /// Returns the content of the markers written by this instance
+    fn content(&self) -> DnsContent {
+        DnsContent::Other(format!("\"{},{}{}\"", HERITAGE, OWNER, &self.owner))
+    }
+}
+
+/// Returns the owner id recorded in `marker`, if it is a marker written by cfdns
+fn owner_id(marker: &DnsRecord) -> Option<String> {
+    let content = marker.content.to_string();
+    let fields = content.trim_matches('"').split(',').collect::<Vec<_>>();
+    if !fields.contains(&HERITAGE) {
+        return None;
+    }
+    fields
+        .iter()
+        .find_map(|field| field.strip_prefix(OWNER))
+        .map(|owner| owner.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn registry(owner: &str) -> Registry {
+        Registry::new(&RegistryConfig {
+            owner: Some(owner.to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn record(name: &str, ty: &str, content: &str) -> DnsRecord {
+        serde_json::from_value(json!({
+            "zone_id": "zone",
+            "name": name,
+            "type": ty,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn is_disabled_without_an_owner() {
+        assert_eq!(Registry::new(&RegistryConfig::default()), None);
+    }
+
+    #[test]
+    fn names_markers_by_type_and_name() {
+        let registry = registry("udm");
+        let a = record("foo.example.com", "A", "1.2.3.4");
+        let aaaa = record("foo.example.com", "AAAA", "::1");
+        assert_eq!(registry.marker_name(&a), "_cfdns.a.foo.example.com");
+        assert_eq!(registry.marker_name(&aaaa), "_cfdns.aaaa.foo.example.com");
+    }
+
+    #[test]
+    fn names_markers_of_wildcards() {
+        let registry = registry("udm");
+        let wildcard = record("*.example.com", "A", "1.2.3.4");
+        assert_eq!(
+            registry.marker_name(&wildcard),
+            "_cfdns.a._wildcard.example.com"
+        );
+    }
+
+    #[test]
+    fn reads_the_owner_of_markers() {
+        let registry = registry("udm");
+        let marker = record("_cfdns.a.foo.example.com", "TXT", "");
+        let marker = DnsRecord {
+            content: registry.content(),
+            ..marker
+        };
+        assert_eq!(owner_id(&marker).as_deref(), Some("udm"));
+
+        let foreign = record(
+            "_cfdns.a.foo.example.com",
+            "TXT",
+            "\"heritage=cfdns,cfdns/owner=other\"",
+        );
+        assert_eq!(owner_id(&foreign).as_deref(), Some("other"));
+
+        // TXT records not written by cfdns don't name an owner, even if they look similar
+        let unrelated = record("_cfdns.a.foo.example.com", "TXT", "\"cfdns/owner=udm\"");
+        assert_eq!(owner_id(&unrelated), None);
+    }
+
+    #[test]
+    fn determines_ownership_from_markers() {
+        let registry = registry("udm");
+        let marker = |owner: &str| {
+            record(
+                "_cfdns.a.foo.example.com",
+                "TXT",
+                &format!("\"heritage=cfdns,cfdns/owner={}\"", owner),
+            )
+        };
+        let unrelated = record("_cfdns.a.foo.example.com", "TXT", "\"hello\"");
+
+        assert_eq!(registry.owner_of(&[]), Ownership::Unowned);
+        assert_eq!(
+            registry.owner_of(std::slice::from_ref(&unrelated)),
+            Ownership::Unowned
+        );
+        assert_eq!(
+            registry.owner_of(&[unrelated.clone(), marker("other")]),
+            Ownership::Foreign("other".to_string())
+        );
+        // This instance's own marker takes precedence over any others
+        assert_eq!(
+            registry.owner_of(&[marker("other"), marker("udm")]),
+            Ownership::Owned
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+/// The granularity of the timer wheel, i.e. the duration of a single tick
+pub const RESOLUTION: Duration = Duration::from_secs(1);
+
+/// The number of slots in the wheel, i.e. how many ticks a single revolution takes
+const SLOTS: usize = 512;
+
+/// A hashed timer wheel, used to schedule items which should expire after some delay.
+///
+/// The wheel is divided into a fixed number of slots, each representing one tick. Scheduling
+/// an item places it in the slot it will expire in, along with the number of full revolutions
+/// of the wheel which must occur first. Advancing the wheel by a tick is O(n) in the number of
+/// items in the current slot, rather than in the total number of scheduled items.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<Entry<T>>>,
+    current: usize,
+}
+
+struct Entry<T> {
+    rounds: usize,
+    item: T,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> TimerWheel<T> {
+    pub fn new() -> Self {
+        let mut slots = Vec::with_capacity(SLOTS);
+        slots.resize_with(SLOTS, Vec::new);
+        Self { slots, current: 0 }
+    }
+
+    /// Schedules `item` to expire after `delay` has elapsed
+    ///
+    /// The delay is rounded up to the nearest tick, and is always at least one tick.
+    pub fn schedule(&mut self, delay: Duration, item: T) {
+        let resolution = RESOLUTION.as_millis();
+        let ticks = delay.as_millis().div_ceil(resolution).max(1) as usize;
+        let slot = (self.current + ticks) % SLOTS;
+        let rounds = (ticks - 1) / SLOTS;
+        self.slots[slot].push(Entry { rounds, item });
+    }
+
+    /// Removes all scheduled occurrences of items matching `predicate`
+    pub fn cancel<P>(&mut self, predicate: P)
+    where
+        P: Fn(&T) -> bool,
+    {
+        for slot in self.slots.iter_mut() {
+            slot.retain(|entry| !predicate(&entry.item));
+        }
+    }
+
+    /// Advances the wheel by a single tick, returning all of the items which expired
+    pub fn tick(&mut self) -> Vec<T> {
+        self.current = (self.current + 1) % SLOTS;
+
+        let mut expired = Vec::new();
+        let slot = &mut self.slots[self.current];
+        let mut i = 0;
+        while i < slot.len() {
+            if slot[i].rounds == 0 {
+                expired.push(slot.swap_remove(i).item);
+            } else {
+                slot[i].rounds -= 1;
+                i += 1;
+            }
+        }
+
+        expired
+    }
+}
+
+/// Applies a random amount of jitter to `interval`, of up to 10% of its duration.
+///
+/// This is used to keep watchers with the same interval from polling in lockstep.
+pub fn jitter(interval: Duration) -> Duration {
+    let max = interval.as_millis() as u64 / 10;
+    interval + Duration::from_millis(fastrand::u64(0..=max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks `wheel` until `item` expires, returning the number of ticks taken
+    fn ticks_until(wheel: &mut TimerWheel<&str>, item: &str, limit: usize) -> Option<usize> {
+        (1..=limit).find(|_| wheel.tick().contains(&item))
+    }
+
+    #[test]
+    fn expires_after_the_delay() {
+        let mut wheel = TimerWheel::new();
+        wheel.schedule(Duration::from_secs(3), "a");
+        assert_eq!(ticks_until(&mut wheel, "a", 10), Some(3));
+        // Expired items aren't returned again
+        assert_eq!(ticks_until(&mut wheel, "a", SLOTS * 2), None);
+    }
+
+    #[test]
+    fn rounds_delays_up_to_at_least_one_tick() {
+        let mut wheel = TimerWheel::new();
+        wheel.schedule(Duration::ZERO, "zero");
+        wheel.schedule(Duration::from_millis(1500), "partial");
+        assert_eq!(wheel.tick(), vec!["zero"]);
+        assert_eq!(ticks_until(&mut wheel, "partial", 10), Some(1));
+    }
+
+    #[test]
+    fn waits_for_whole_revolutions() {
+        let mut wheel = TimerWheel::new();
+        let ticks = SLOTS * 2 + 5;
+        wheel.schedule(RESOLUTION * ticks as u32, "a");
+        wheel.schedule(RESOLUTION * SLOTS as u32, "b");
+        assert_eq!(ticks_until(&mut wheel, "b", ticks), Some(SLOTS));
+        assert_eq!(ticks_until(&mut wheel, "a", ticks), Some(ticks - SLOTS));
+    }
+
+    #[test]
+    fn reschedules_after_cancelling() {
+        let mut wheel = TimerWheel::new();
+        wheel.schedule(Duration::from_secs(2), "a");
+        wheel.schedule(Duration::from_secs(2), "b");
+        wheel.tick();
+
+        // Rescheduling relies on cancelling the existing occurrence first
+        wheel.cancel(|item| *item == "a");
+        wheel.schedule(Duration::from_secs(5), "a");
+        assert_eq!(wheel.tick(), vec!["b"]);
+        assert_eq!(ticks_until(&mut wheel, "a", 10), Some(4));
+    }
+
+    #[test]
+    fn jitter_is_at_most_a_tenth_of_the_interval() {
+        let interval = Duration::from_secs(60);
+        for _ in 0..100 {
+            let jittered = jitter(interval);
+            assert!(jittered >= interval);
+            assert!(jittered <= interval + Duration::from_secs(6));
+        }
+    }
+}
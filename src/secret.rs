@@ -0,0 +1,66 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+/// The text which stands in for a secret wherever it might otherwise be displayed
+const REDACTED: &str = "<redacted>";
+
+/// This type represents a sensitive string, such as an API token
+///
+/// The value is redacted when formatted via `Debug`, or serialized, so that it can't leak into
+/// logs, persisted state, or the output of commands. Use `expose` to access the actual value.
+#[derive(Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Self {
+        Self(value.into())
+    }
+
+    /// Returns the actual value of this secret
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+impl Serialize for Secret {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_debug_output() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret(<redacted>))");
+    }
+
+    #[test]
+    fn redacts_serialized_output() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"<redacted>\"");
+    }
+
+    #[test]
+    fn deserializes_the_actual_value() {
+        let secret: Secret = serde_json::from_str("\"hunter2\"").unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+        assert!(!secret.is_empty());
+    }
+}
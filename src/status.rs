@@ -0,0 +1,171 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloudflare::{DnsContent, DnsRecord, DnsRecordType, Id, ProxyMode, Ttl};
+use crate::config::Interval;
+use crate::time::Timestamp;
+use crate::watcher::Watcher;
+
+/// This enum represents the overall synchronization status of a watcher
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatcherStatus {
+    Disabled,
+    Synced,
+    OutOfSync,
+    Failed,
+}
+
+/// This enum represents the status of a single record, relative to its state in Cloudflare
+///
+/// When serialized, the variant is stored in `status`, and its data (if any) in `detail`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "detail")]
+pub enum CloudflareStatus {
+    OK,
+    Missing,
+    TypeMismatch(DnsRecordType),
+    OutOfSync,
+    Error(String),
+}
+impl fmt::Display for CloudflareStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TypeMismatch(ty) => write!(f, "TypeMismatch({})", ty),
+            Self::Error(reason) => write!(f, "Error({})", reason),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// This struct represents the synchronization status of a single record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncStatus {
+    pub name: String,
+    pub zone: Id,
+    #[serde(rename = "type")]
+    pub ty: DnsRecordType,
+    pub local: DnsContent,
+    pub upstream: Option<DnsContent>,
+    #[serde(flatten)]
+    pub status: CloudflareStatus,
+    pub proxied: ProxyMode,
+    pub ttl: Ttl,
+    /// The comment on the record in Cloudflare
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The tags on the record in Cloudflare
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The number of records in Cloudflare with this name and type, if there is more than one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<usize>,
+    /// The last time this record was created or updated in Cloudflare by this process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<Timestamp>,
+}
+impl SyncStatus {
+    /// Compares the desired state of `local` against what Cloudflare has for the same name
+    ///
+    /// If Cloudflare has duplicates of the record, it is only in sync if all of them are.
+    pub fn compare(local: &DnsRecord, upstream: anyhow::Result<Vec<DnsRecord>>) -> Self {
+        let mut sync = Self {
+            name: local.name.clone(),
+            zone: local.zone_id.clone(),
+            ty: local.ty,
+            local: local.content.clone(),
+            upstream: None,
+            status: CloudflareStatus::Missing,
+            proxied: ProxyMode::default(),
+            ttl: Ttl::default(),
+            comment: None,
+            tags: Vec::new(),
+            duplicates: None,
+            changed: None,
+        };
+        let upstream = upstream.map(|upstream| {
+            if upstream.len() > 1 {
+                sync.duplicates = Some(upstream.len());
+            }
+            // Of any duplicates, the first which is out of sync is the one reported
+            let index = upstream
+                .iter()
+                .position(|r| r.content != sync.local)
+                .unwrap_or(0);
+            upstream.into_iter().nth(index)
+        });
+        match upstream {
+            Ok(None) => {}
+            Ok(Some(upstream)) => {
+                sync.proxied = upstream.proxied;
+                sync.ttl = upstream.ttl;
+                sync.comment = upstream.comment;
+                sync.tags = upstream.tags.unwrap_or_default();
+                if sync.ty != upstream.ty {
+                    sync.status = CloudflareStatus::TypeMismatch(upstream.ty);
+                } else if sync.local == upstream.content {
+                    sync.status = CloudflareStatus::OK;
+                } else {
+                    sync.status = CloudflareStatus::OutOfSync;
+                }
+                sync.upstream = Some(upstream.content);
+            }
+            Err(e) => {
+                sync.status = CloudflareStatus::Error(format!("{}", &e));
+            }
+        }
+        sync
+    }
+
+    /// Builds a status for `record`, which has just been successfully synced to Cloudflare
+    pub fn synced(record: &DnsRecord) -> Self {
+        Self::compare(record, Ok(vec![record.clone()]))
+    }
+
+    /// Builds a status for `record`, which could not be synced to Cloudflare
+    pub fn failed(record: &DnsRecord, error: &anyhow::Error) -> Self {
+        Self::compare(record, Err(anyhow::anyhow!("{:#}", error)))
+    }
+}
+
+/// This struct summarizes the synchronization status of a single watcher and its records
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatcherReport {
+    pub interface: String,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub interval: Interval,
+    pub status: WatcherStatus,
+    pub records: Vec<SyncStatus>,
+}
+impl WatcherReport {
+    pub fn new(watcher: &Watcher, records: Vec<SyncStatus>) -> Self {
+        let info = &watcher.interface.info;
+        let mut status = WatcherStatus::Synced;
+        for record in records.iter() {
+            match &record.status {
+                CloudflareStatus::Error(_) => {
+                    status = WatcherStatus::Failed;
+                }
+                CloudflareStatus::OK => {}
+                _ => {
+                    status = WatcherStatus::OutOfSync;
+                }
+            }
+        }
+        // If there are no records to sync, the watcher is disabled automatically
+        if records.is_empty() {
+            status = WatcherStatus::Disabled;
+        }
+
+        Self {
+            interface: watcher.interface.name.clone(),
+            ipv4: info.v4(),
+            ipv6: info.v6(),
+            interval: watcher.interface.interval,
+            status,
+            records,
+        }
+    }
+}
@@ -41,7 +41,7 @@ impl Platform {
             return Ok(Self::Other);
         }
 
-        let mut cmd = Command::new(&path);
+        let mut cmd = Command::new(path);
         cmd.arg("model_short");
 
         let output = match cmd.stderr(Stdio::inherit()).output() {
@@ -96,6 +96,14 @@ impl InterfaceInfo {
         self.v4.is_some() || self.v6.is_some()
     }
 
+    pub fn v4(&self) -> Option<Ipv4Addr> {
+        self.v4
+    }
+
+    pub fn v6(&self) -> Option<Ipv6Addr> {
+        self.v6
+    }
+
     pub fn address(&self, ty: AddressFamily) -> Option<IpAddr> {
         match ty {
             AddressFamily::IPv4 => self.v4.map(IpAddr::V4),
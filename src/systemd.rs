@@ -0,0 +1,106 @@
+//! This module implements the `sd_notify` protocol, used to integrate with systemd services of `Type=notify`.
+//!
+//! See `man sd_notify` for details. When not running under systemd (i.e. `NOTIFY_SOCKET` is not set),
+//! all notifications are silently ignored.
+use std::env;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// The interval at which the watchdog must be pinged, if enabled
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+impl Notifier {
+    /// Creates a notifier from the environment provided by systemd
+    pub fn from_env() -> Self {
+        let socket = match env::var_os("NOTIFY_SOCKET") {
+            None => None,
+            Some(path) => match connect(&path.to_string_lossy()) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("Unable to use systemd notification socket: {}", e);
+                    None
+                }
+            },
+        };
+
+        // The watchdog is only meant for us if WATCHDOG_PID is unset, or matches our pid
+        let for_us = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .map(|pid| pid == std::process::id())
+            .unwrap_or(true);
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| for_us && *usec > 0)
+            .map(Duration::from_micros);
+
+        Self {
+            socket,
+            watchdog,
+            last_ping: None,
+        }
+    }
+
+    /// Notifies systemd that startup is complete
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Updates the free-form status of the service, as shown by `systemctl status`
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Pings the watchdog, if enabled
+    ///
+    /// This is safe to call as often as desired, pings are only sent at half the watchdog interval.
+    pub fn watchdog(&mut self) {
+        let interval = match self.watchdog {
+            None => return,
+            Some(interval) => interval,
+        };
+        let due = self
+            .last_ping
+            .map(|last| last.elapsed() >= interval / 2)
+            .unwrap_or(true);
+        if due {
+            self.notify("WATCHDOG=1");
+            self.last_ping = Some(Instant::now());
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, addr)) = self.socket.as_ref() {
+            debug!("Sending systemd notification: {}", state);
+            if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+                warn!("Failed to send systemd notification: {}", e);
+            }
+        }
+    }
+}
+
+fn connect(path: &str) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+    let addr = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract socket addresses are only supported on Linux",
+            ))
+        }
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    Ok((socket, addr))
+}
@@ -0,0 +1,96 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// This type represents a point in time, which is rendered as an RFC 3339 timestamp
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Timestamp(SystemTime);
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+
+    /// Returns the amount of time which has elapsed since this timestamp, or zero if it is in the future
+    pub fn elapsed(&self) -> Duration {
+        self.0.elapsed().unwrap_or_default()
+    }
+
+    /// Returns the amount of time between `earlier` and this timestamp, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        self.0.duration_since(earlier.0).unwrap_or_default()
+    }
+
+    /// Returns the number of seconds since the Unix epoch
+    pub fn unix_seconds(&self) -> u64 {
+        self.0
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+impl std::ops::Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0 + rhs)
+    }
+}
+impl std::ops::Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        Self(self.0 - rhs)
+    }
+}
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    /// Parses either an RFC 3339 timestamp, or a duration (e.g. `7d`), which is relative to now
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(time) = humantime::parse_rfc3339_weak(s) {
+            return Ok(Self(time));
+        }
+        match humantime::parse_duration(s) {
+            Ok(ago) => Ok(Self::now() - ago),
+            Err(_) => Err(anyhow::anyhow!(
+                "invalid time '{}', expected a timestamp (e.g. 2022-01-31T12:00:00Z) or a duration (e.g. 7d)",
+                s
+            )),
+        }
+    }
+}
+impl From<SystemTime> for Timestamp {
+    #[inline]
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", humantime::format_rfc3339_seconds(self.0))
+    }
+}
+impl Serialize for Timestamp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        humantime::parse_rfc3339_weak(&s)
+            .map(Self)
+            .map_err(|e| Error::custom(format!("invalid timestamp '{}': {}", s, e)))
+    }
+}
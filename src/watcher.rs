@@ -1,81 +1,959 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
 use anyhow::anyhow;
 use log::{info, warn};
 
+use crate::audit::{self, Action, AuditEntry};
 use crate::cloudflare::*;
-use crate::config::Interface;
-use crate::system::IfConfig;
+use crate::config::file::DuplicatePolicy;
+use crate::config::{Interface, Interval};
+use crate::health::Health;
+use crate::hooks::{Change, RecordHooks};
+use crate::metrics;
+use crate::notify::{self, Event};
+use crate::plan::{Operation, RecordPlan};
+use crate::reconcile::{self, Duplicates, RecordKey};
+use crate::registry::{Ownership, Registry};
+use crate::status::{CloudflareStatus, SyncStatus};
+use crate::system::{AddressFamily, IfConfig};
+use crate::time::Timestamp;
+
+/// Uniquely identifies a watcher across configuration reloads
+///
+/// Watchers are created for each unique combination of interface and API credentials,
+/// so that pair is sufficient to determine whether two watchers are the same.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WatcherKey {
+    pub interface: String,
+    pub credentials: Credentials,
+}
+
+/// Captures everything about a watcher's configuration which affects its behavior
+///
+/// Two watchers with the same key and fingerprint are interchangeable, so this is
+/// used to determine whether a running watcher needs to be replaced after a reload.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    interval: Interval,
+    records: HashSet<RecordFingerprint>,
+    hooks: HashMap<(String, DnsRecordType), RecordHooks>,
+    duplicates: HashMap<(String, DnsRecordType), DuplicatePolicy>,
+    registry: Option<Registry>,
+}
+
+/// The fields of a watched record which affect how it is synced
+type RecordFingerprint = (
+    Id,
+    String,
+    DnsRecordType,
+    ProxyMode,
+    Ttl,
+    Option<String>,
+    Option<Vec<String>>,
+);
 
 pub struct Watcher {
     pub client: Cloudflare,
-    pub token: String,
+    pub credentials: Credentials,
     pub interface: Interface,
     pub watching: Vec<Zone>,
+    /// Tracks the outcome of polls when running as a daemon
+    pub health: Health,
+    /// The status of each watched record as of the most recent poll
+    pub statuses: Vec<SyncStatus>,
+    /// The hooks to run when each watched record changes, keyed by record name and type
+    pub hooks: HashMap<(String, DnsRecordType), RecordHooks>,
+    /// What to do with duplicates of each watched record, keyed by record name and type
+    pub duplicates: HashMap<(String, DnsRecordType), DuplicatePolicy>,
+    /// The names of records which should not be synced
+    pub paused: HashSet<String>,
+    /// Cloudflare's view of each watched record, as of the most recent poll
+    ///
+    /// There may be more than one record in Cloudflare with the same name and type.
+    pub upstream: HashMap<RecordKey, Vec<DnsRecord>>,
+    /// Tracks which records this instance owns, if ownership is tracked
+    pub registry: Option<Registry>,
 }
 impl Watcher {
-    pub fn new(interface: Interface, token: String) -> anyhow::Result<Self> {
-        Ok(Self {
-            client: Cloudflare::new(token.clone())?,
-            token,
+    pub fn new(interface: Interface, credentials: Credentials, client: Cloudflare) -> Self {
+        Self {
+            client,
+            credentials,
             interface,
             watching: Vec::new(),
-        })
+            health: Health::default(),
+            statuses: Vec::new(),
+            hooks: HashMap::new(),
+            duplicates: HashMap::new(),
+            paused: HashSet::new(),
+            upstream: HashMap::new(),
+            registry: None,
+        }
+    }
+
+    /// Returns the key which identifies this watcher across configuration reloads
+    pub fn key(&self) -> WatcherKey {
+        WatcherKey {
+            interface: self.interface.name.clone(),
+            credentials: self.credentials.clone(),
+        }
+    }
+
+    /// Returns a fingerprint of this watcher's configuration
+    pub fn fingerprint(&self) -> Fingerprint {
+        let records = self
+            .watching
+            .iter()
+            .flat_map(|zone| {
+                zone.records.iter().map(|record| {
+                    (
+                        zone.id.clone(),
+                        record.name.clone(),
+                        record.ty,
+                        record.proxied,
+                        record.ttl,
+                        record.comment.clone(),
+                        record.tags.clone(),
+                    )
+                })
+            })
+            .collect();
+
+        Fingerprint {
+            interval: self.interface.interval,
+            records,
+            hooks: self.hooks.clone(),
+            duplicates: self.duplicates.clone(),
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Returns true if this watcher syncs a record with the given name
+    pub fn syncs(&self, record: &str) -> bool {
+        self.watching
+            .iter()
+            .flat_map(|zone| zone.records.iter())
+            .any(|r| r.name == record)
+    }
+
+    /// Describes each record which failed to sync in the most recent poll, along with its zone
+    ///
+    /// If the poll failed before any record could be synced, e.g. because the interface couldn't
+    /// be found, all of the watched records are described.
+    pub fn failing(&self) -> Vec<String> {
+        let zone = |id: &Id| {
+            self.watching
+                .iter()
+                .find(|zone| &zone.id == id)
+                .map(|zone| zone.name.clone())
+                .unwrap_or_else(|| id.to_string())
+        };
+        let failing = self
+            .statuses
+            .iter()
+            .filter(|s| matches!(s.status, CloudflareStatus::Error(_)))
+            .map(|s| format!("{} ({}) in {}", &s.name, &s.ty, zone(&s.zone)))
+            .collect::<Vec<_>>();
+        if !failing.is_empty() {
+            return failing;
+        }
+        self.watching
+            .iter()
+            .flat_map(|z| z.records.iter().map(move |r| (z, r)))
+            .map(|(z, r)| format!("{} ({}) in {}", &r.name, &r.ty, &z.name))
+            .collect()
+    }
+
+    /// Returns what to do with duplicates of `record` in Cloudflare
+    fn policy(&self, record: &DnsRecord) -> DuplicatePolicy {
+        self.duplicates
+            .get(&(record.name.clone(), record.ty))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Syncs all watched records using the interface info from the given snapshot
+    pub fn poll(&mut self, ifconfig: &IfConfig) -> anyhow::Result<()> {
+        let result = self.sync(ifconfig);
+        metrics::poll(&self.interface.name, result.is_ok());
+        result
     }
 
-    pub fn poll(&mut self) -> anyhow::Result<()> {
+    /// Plans the operations needed to sync all watched records, without changing anything
+    ///
+    /// Like a poll, this lists the records in each watched zone in Cloudflare, and if ownership is
+    /// tracked, looks up the owner of each record which would change. It makes no other requests,
+    /// and none which change anything, runs no hooks, and emits no notifications.
+    pub fn plan(&mut self, ifconfig: &IfConfig) -> anyhow::Result<Vec<RecordPlan>> {
+        let info = ifconfig
+            .get(&self.interface.name)
+            .ok_or_else(|| anyhow!("Unable to load interface '{}'", &self.interface.name))?;
+
+        let mut plans = Vec::new();
+        for zone in self.watching.iter() {
+            refresh(&self.client, &mut self.upstream, zone)?;
+            for record in zone.records.iter() {
+                let family = record.ty.try_into().unwrap();
+                let operations = match info.address(family) {
+                    _ if self.paused.contains(&record.name) => vec![Operation::Skip {
+                        reason: "syncing is paused".to_string(),
+                    }],
+                    Some(addr) => {
+                        let desired = desired(record, addr);
+                        let registry = self.registry.as_ref();
+                        let policy = self.policy(record);
+                        match prepare(&self.client, &self.upstream, registry, policy, &desired) {
+                            Ok(prepared) => prepared.into_iter().map(|(_, op)| op).collect(),
+                            Err(e) if e.is::<Duplicates>() => vec![Operation::Skip {
+                                reason: e.to_string(),
+                            }],
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    None => vec![Operation::Skip {
+                        reason: format!(
+                            "No {} address bound to {}",
+                            &record.ty, &self.interface.name
+                        ),
+                    }],
+                };
+                for operation in operations {
+                    plans.push(RecordPlan {
+                        interface: self.interface.name.clone(),
+                        zone: zone.name.clone(),
+                        name: record.name.clone(),
+                        ty: record.ty,
+                        operation,
+                    });
+                }
+            }
+        }
+
+        Ok(plans)
+    }
+
+    fn sync(&mut self, ifconfig: &IfConfig) -> anyhow::Result<()> {
         info!("Checking for updates to {}", &self.interface.name);
 
         // Fetch latest interface info
-        let ifconfig = IfConfig::new();
         let info = ifconfig
             .get(&self.interface.name)
             .ok_or_else(|| anyhow!("Unable to load interface '{}'", &self.interface.name))?;
 
-        // Update watcher-local info
+        // Update watcher-local info, notifying of any address changes
+        for family in [AddressFamily::IPv4, AddressFamily::IPv6] {
+            let old = self.interface.info.address(family);
+            let new = info.address(family);
+            metrics::address(&self.interface.name, family, new);
+            let records = self
+                .watching
+                .iter()
+                .flat_map(|zone| zone.records.iter())
+                .filter(|record| record.ty == DnsRecordType::from(family))
+                .map(|record| record.name.clone())
+                .collect::<Vec<_>>();
+            match new {
+                Some(new) if old != Some(new) && !records.is_empty() => {
+                    info!("Address of {} changed to {}", &self.interface.name, &new);
+                    notify::emit(Event::AddressChanged {
+                        interface: self.interface.name.clone(),
+                        old,
+                        new,
+                        records,
+                    });
+                }
+                _ => continue,
+            }
+        }
         self.interface.info = info.clone();
 
-        // Traverse each watched zone, syncing any records which are changed as a result of the poll
-        for zone in self.watching.iter_mut() {
-            for record in zone.records.iter_mut() {
-                if let Some(addr) = self.interface.info.address(record.ty.try_into().unwrap()) {
-                    // If we don't yet know the record identifier, ask Cloudflare for it
-                    if record.id.is_none() {
-                        info!("Looking up record metadata for {}", &record.name);
-                        // Update our local view of the record with data from Cloudflare
-                        if let Some(found) = self.client.get(&zone.id, &record.name, record.ty)? {
-                            info!(
-                                "Found {} record in Cloudflare for {}: {}",
-                                &found.ty, &found.name, &found.content
-                            );
-                            *record = found;
-                        } else {
-                            info!("No record of {} in Cloudflare", &record.name);
-                        }
+        // Records with duplicates are left alone, but still cause the poll to fail once the rest
+        // are synced, so that they're noticed
+        let mut result = Ok(());
+
+        // Traverse each watched zone, planning the changes needed to sync its records, which are
+        // then applied to the zone all at once
+        for zone in self.watching.iter() {
+            if let Err(e) = refresh(&self.client, &mut self.upstream, zone) {
+                for record in zone.records.iter() {
+                    update_status(&mut self.statuses, SyncStatus::failed(record, &e));
+                }
+                return Err(e);
+            }
+
+            let mut pending = Vec::new();
+            for record in zone.records.iter() {
+                if self.paused.contains(&record.name) {
+                    info!("Skipping {}, syncing is paused", &record.name);
+                    continue;
+                }
+                let family = record.ty.try_into().unwrap();
+                let addr = match self.interface.info.address(family) {
+                    Some(addr) => addr,
+                    None => {
+                        warn!(
+                            "Unable to find interface address for {} of appropriate type for {} record",
+                            &record.name, &record.ty
+                        );
+                        let error = anyhow!(
+                            "No {} address bound to {}",
+                            &record.ty,
+                            &self.interface.name
+                        );
+                        update_status(&mut self.statuses, SyncStatus::failed(record, &error));
+                        continue;
+                    }
+                };
+
+                let desired = desired(record, addr);
+                let prepared = prepare(
+                    &self.client,
+                    &self.upstream,
+                    self.registry.as_ref(),
+                    self.policy(record),
+                    &desired,
+                );
+                let prepared = match prepared {
+                    Ok(prepared) => prepared,
+                    Err(e) if e.is::<Duplicates>() => {
+                        warn!("Skipping {}, {}", &desired.name, &e);
+                        update_status(&mut self.statuses, SyncStatus::failed(&desired, &e));
+                        result = Err(e);
+                        continue;
+                    }
+                    Err(e) => {
+                        update_status(&mut self.statuses, SyncStatus::failed(&desired, &e));
+                        return Err(e);
                     }
-                    // Apply the current interface address, and if the content changes, update the record in Cloudflare
-                    if record.id.is_some() {
-                        if record.try_update(addr)? {
-                            info!("Updating {} with new address {}", &record.name, &addr);
-                            self.client.update(record)?;
-                        } else {
-                            info!("{} is up to date!", &record.name);
+                };
+                for (current, operation) in prepared {
+                    match operation {
+                        Operation::NoOp => {
+                            info!("{} is up to date!", &desired.name);
+                            metrics::record_synced(&zone.name, &desired.name, desired.ty);
+                            let current = current.unwrap_or_else(|| desired.clone());
+                            update_status(&mut self.statuses, SyncStatus::synced(&current));
+                        }
+                        // Records owned by someone else are left alone, but don't cause the poll to fail
+                        Operation::Skip { reason } => {
+                            warn!("Skipping {}, {}", &desired.name, &reason);
+                            let error = anyhow!(reason);
+                            update_status(&mut self.statuses, SyncStatus::failed(&desired, &error));
+                        }
+                        operation => {
+                            let hooks = self
+                                .hooks
+                                .get(&(record.name.clone(), record.ty))
+                                .cloned()
+                                .unwrap_or_default();
+                            pending.push(Pending::new(
+                                &self.interface.name,
+                                &zone.name,
+                                desired.clone(),
+                                current,
+                                &operation,
+                                hooks,
+                            ));
                         }
-                    } else {
-                        info!("Creating {} with address {}", &record.name, &addr);
-                        // Make sure the record has current content
-                        record.content = addr.into();
-                        self.client.create(record)?;
                     }
-                } else {
-                    warn!(
-                        "Unable to find interface address for {} of appropriate type for {} record",
-                        &record.name, &record.ty
-                    );
                 }
             }
+
+            apply(
+                &self.client,
+                self.registry.as_ref(),
+                &mut self.upstream,
+                &mut self.statuses,
+                zone,
+                pending,
+            )?;
         }
 
         info!("Sync for {} is complete!", &self.interface.name);
 
+        result
+    }
+}
+
+/// Returns the desired state of `record` in Cloudflare, when bound to `addr`
+fn desired(record: &DnsRecord, addr: IpAddr) -> DnsRecord {
+    DnsRecord {
+        content: addr.into(),
+        ..record.clone()
+    }
+}
+
+/// Replaces Cloudflare's view of the records watched in `zone` with their current state
+fn refresh(
+    client: &Cloudflare,
+    upstream: &mut HashMap<RecordKey, Vec<DnsRecord>>,
+    zone: &Zone,
+) -> anyhow::Result<()> {
+    info!("Listing records in {}", &zone.name);
+    let records = reconcile::list(client, zone, true)?;
+    upstream.retain(|(zone_id, _, _), _| *zone_id != zone.id);
+    for record in records {
+        upstream
+            .entry(reconcile::key(&record))
+            .or_default()
+            .push(record);
+    }
+    Ok(())
+}
+
+/// Determines the operations needed to sync `desired`, each along with Cloudflare's view of the
+/// record it applies to
+///
+/// Fails with `Duplicates` if there are duplicates of the record, and `policy` doesn't allow them.
+fn prepare(
+    client: &Cloudflare,
+    upstream: &HashMap<RecordKey, Vec<DnsRecord>>,
+    registry: Option<&Registry>,
+    policy: DuplicatePolicy,
+    desired: &DnsRecord,
+) -> anyhow::Result<Vec<(Option<DnsRecord>, Operation)>> {
+    let existing = upstream
+        .get(&reconcile::key(desired))
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut prepared = Vec::new();
+    for (current, operation) in reconcile::reconcile(desired, existing, policy)? {
+        let operation = authorize(client, registry, desired, operation)?;
+        prepared.push((current.cloned(), operation));
+    }
+
+    Ok(prepared)
+}
+
+/// Skips `operation` if it would change a record which isn't owned by this instance
+///
+/// Ownership is only checked if it is tracked, and the operation would change something.
+fn authorize(
+    client: &Cloudflare,
+    registry: Option<&Registry>,
+    desired: &DnsRecord,
+    operation: Operation,
+) -> anyhow::Result<Operation> {
+    let registry = match registry {
+        Some(registry)
+            if matches!(
+                operation,
+                Operation::Create { .. } | Operation::Update { .. } | Operation::Delete { .. }
+            ) =>
+        {
+            registry
+        }
+        _ => return Ok(operation),
+    };
+    let reason = match (&operation, registry.ownership(client, desired)?) {
+        (_, Ownership::Foreign(owner)) => format!("it is owned by '{}'", owner),
+        (Operation::Update { .. } | Operation::Delete { .. }, Ownership::Unowned) => {
+            "it isn't owned by this instance, use `cfdns adopt` to take it over".to_string()
+        }
+        _ => return Ok(operation),
+    };
+
+    Ok(Operation::Skip { reason })
+}
+
+/// A change to a single record, which is made along with any other changes to the same zone
+struct Pending {
+    action: Action,
+    /// The record as it was in Cloudflare before the change, if it existed
+    current: Option<DnsRecord>,
+    /// The record to send to Cloudflare, which is replaced with Cloudflare's copy once changed
+    record: DnsRecord,
+    change: Change,
+    hooks: RecordHooks,
+    /// Whether the marker of the record was created for this change, and so should be removed if it isn't made
+    claimed: bool,
+    /// Whether the change sets the comment and tags of the record, rather than leaving them as they are
+    sets_comment: bool,
+    sets_tags: bool,
+}
+impl Pending {
+    fn new(
+        interface: &str,
+        zone: &str,
+        desired: DnsRecord,
+        current: Option<DnsRecord>,
+        operation: &Operation,
+        hooks: RecordHooks,
+    ) -> Self {
+        let action = match operation {
+            Operation::Delete { .. } => Action::Delete,
+            _ if current.is_some() => Action::Update,
+            _ => Action::Create,
+        };
+        let sets_comment = action != Action::Delete && desired.comment.is_some();
+        let sets_tags = action != Action::Delete && desired.tags.is_some();
+        let record = match current.as_ref() {
+            Some(current) if action == Action::Delete => current.clone(),
+            // Only the fields we manage are changed, everything else is kept as it is in Cloudflare
+            Some(current) => DnsRecord {
+                content: desired.content.clone(),
+                proxied: desired.proxied,
+                ttl: desired.ttl,
+                comment: desired.comment.clone(),
+                tags: desired.tags.clone(),
+                ..current.clone()
+            },
+            None => desired,
+        };
+        match operation {
+            Operation::Update { changes, .. } => {
+                let changes = changes
+                    .iter()
+                    .map(|change| change.to_string())
+                    .collect::<Vec<_>>();
+                info!("Updating {} ({})", &record.name, changes.join(", "));
+            }
+            Operation::Delete { .. } => info!(
+                "Deleting duplicate {} with address {}",
+                &record.name, &record.content
+            ),
+            _ => info!("Creating {} with address {}", &record.name, &record.content),
+        }
+        let change = Change {
+            action: match action {
+                Action::Create => "create",
+                Action::Update => "update",
+                Action::Delete => "delete",
+            },
+            interface: interface.to_string(),
+            zone: zone.to_string(),
+            record: record.name.clone(),
+            ty: record.ty,
+            old: current.as_ref().map(|current| current.content.clone()),
+            new: record.content.clone(),
+        };
+
+        Self {
+            action,
+            current,
+            record,
+            change,
+            // Hooks are only run for changes to the records in the config, not their duplicates
+            hooks: match action {
+                Action::Delete => RecordHooks::default(),
+                _ => hooks,
+            },
+            claimed: false,
+            sets_comment,
+            sets_tags,
+        }
+    }
+
+    /// Prepares to make the change, running any pre-change hooks, which may veto it
+    fn begin(&mut self, client: &Cloudflare, registry: Option<&Registry>) -> anyhow::Result<()> {
+        self.hooks.pre(&self.change)?;
+        // Claim a record before creating it, so that it's never left without an owner
+        if let (Action::Create, Some(registry)) = (self.action, registry) {
+            self.claimed = registry.claim(client, &self.record, false)? == Ownership::Unowned;
+        }
         Ok(())
     }
+
+    /// Removes the marker claimed by `begin`, once it's known that the record wasn't created
+    fn abandon(&self, client: &Cloudflare, registry: Option<&Registry>) {
+        if let (true, Some(registry)) = (self.claimed, registry) {
+            if let Err(e) = registry.release(client, &self.record) {
+                warn!(
+                    "Unable to release ownership of {} ({}): {:#}",
+                    &self.record.name, &self.record.ty, e
+                );
+            }
+        }
+    }
+
+    /// Runs any post-change hooks, and reports the change, once it has been made
+    fn complete(&self) {
+        let change = &self.change;
+        self.hooks.post(change);
+        metrics::record_updated(&change.zone, &change.record, change.ty, change.action);
+        notify::emit(match (self.action, self.current.as_ref()) {
+            // The record itself is still in place after a duplicate is deleted, so there's nothing to notify
+            (Action::Delete, _) => return,
+            (_, Some(current)) => Event::RecordUpdated {
+                interface: change.interface.clone(),
+                zone: change.zone.clone(),
+                record: change.record.clone(),
+                ty: change.ty,
+                old: current.content.clone(),
+                new: self.record.content.clone(),
+            },
+            (_, None) => Event::RecordCreated {
+                interface: change.interface.clone(),
+                zone: change.zone.clone(),
+                record: change.record.clone(),
+                ty: change.ty,
+                content: self.record.content.clone(),
+            },
+        });
+    }
+}
+
+/// Makes the pending changes to the records of `zone`, updating the status of each record
+///
+/// Changes vetoed by a hook are left out, but the rest are still made.
+fn apply(
+    client: &Cloudflare,
+    registry: Option<&Registry>,
+    upstream: &mut HashMap<RecordKey, Vec<DnsRecord>>,
+    statuses: &mut Vec<SyncStatus>,
+    zone: &Zone,
+    mut pending: Vec<Pending>,
+) -> anyhow::Result<()> {
+    let mut vetoed = Ok(());
+    pending.retain_mut(|p| match p.begin(client, registry) {
+        Ok(()) => true,
+        Err(e) => {
+            update_status(statuses, SyncStatus::failed(&p.record, &e));
+            vetoed = Err(e);
+            false
+        }
+    });
+    if pending.is_empty() {
+        return vetoed;
+    }
+
+    if let Err(e) = commit(client, zone, &mut pending) {
+        // None of the changes were made, or they've been rolled back, so none of the records were created
+        for p in pending.iter() {
+            p.abandon(client, registry);
+            update_status(statuses, SyncStatus::failed(&p.record, &e));
+        }
+        return Err(e);
+    }
+    for p in pending.iter() {
+        p.complete();
+        let records = upstream.entry(reconcile::key(&p.record)).or_default();
+        records.retain(|r| r.id != p.record.id);
+        if p.action == Action::Delete {
+            continue;
+        }
+        records.push(p.record.clone());
+        metrics::record_synced(&zone.name, &p.record.name, p.record.ty);
+        let mut status = SyncStatus::synced(&p.record);
+        status.changed = Some(Timestamp::now());
+        update_status(statuses, status);
+    }
+
+    vetoed
+}
+
+/// Makes the pending changes to the records of `zone` in Cloudflare, all at once
+///
+/// Multiple changes are submitted together via the batch endpoint, so either all of them are made,
+/// or none are. If the batch endpoint isn't available, the changes are made one at a time instead,
+/// and if any of them fails, those already made are rolled back.
+fn commit(client: &Cloudflare, zone: &Zone, pending: &mut [Pending]) -> anyhow::Result<()> {
+    if pending.len() > 1 {
+        let (deletes, changes): (Vec<_>, Vec<_>) =
+            pending.iter_mut().partition(|p| p.action == Action::Delete);
+        let deletes = deletes.iter().map(|p| &p.record).collect::<Vec<_>>();
+        let mut records = changes
+            .into_iter()
+            .map(|p| &mut p.record)
+            .collect::<Vec<_>>();
+        match client.batch(&zone.id, &deletes, &mut records) {
+            Err(e) if batch_unavailable(&e) => warn!(
+                "Unable to change the records in {} as a batch, changing them one at a time: {:#}",
+                &zone.name, e
+            ),
+            result => {
+                for p in pending.iter() {
+                    audit(p.action, &p.change, &p.record, &result);
+                }
+                return result.map(|_| ());
+            }
+        }
+    }
+
+    for i in 0..pending.len() {
+        let p = &mut pending[i];
+        let result = match p.action {
+            Action::Create => client.create(&mut p.record),
+            Action::Update => client.update(&mut p.record),
+            Action::Delete => client.delete(&p.record),
+        };
+        audit(p.action, &p.change, &p.record, &result);
+        if let Err(e) = result {
+            if i > 0 {
+                warn!("Rolling back {} change(s) to {}", i, &zone.name);
+                rollback(client, &mut pending[..i]);
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if `error` shows that the batch endpoint isn't available, e.g. via an API proxy
+fn batch_unavailable(error: &anyhow::Error) -> bool {
+    let status = api_error(error).and_then(|e| e.status());
+    matches!(status, Some(404 | 405 | 501))
+}
+
+/// Reverts changes which were made before a later change to the same zone failed
+///
+/// Since there's nothing more that can be done if reverting a change fails, failures are logged.
+fn rollback(client: &Cloudflare, applied: &mut [Pending]) {
+    for p in applied.iter_mut().rev() {
+        let (action, restored, result) = match (p.action, p.current.as_ref()) {
+            // A deleted record can't be restored as-is, so it is recreated with the same settings
+            (Action::Delete, _) => {
+                let mut recreated = DnsRecord {
+                    id: None,
+                    unmanaged: Default::default(),
+                    ..p.record.clone()
+                };
+                let result = client.create(&mut recreated);
+                (Action::Create, Some(recreated), result)
+            }
+            (_, Some(current)) => {
+                // The comment and tags are only restored if the change set them, otherwise they're left alone
+                let mut restored = DnsRecord {
+                    comment: p
+                        .sets_comment
+                        .then(|| current.comment.clone().unwrap_or_default()),
+                    tags: p
+                        .sets_tags
+                        .then(|| current.tags.clone().unwrap_or_default()),
+                    ..current.clone()
+                };
+                let result = client.update(&mut restored);
+                (Action::Update, Some(restored), result)
+            }
+            (_, None) => (Action::Delete, None, client.delete(&p.record)),
+        };
+        audit::record(&AuditEntry {
+            timestamp: Timestamp::now(),
+            action,
+            interface: p.change.interface.clone(),
+            zone: p.change.zone.clone(),
+            record: p.change.record.clone(),
+            ty: p.change.ty,
+            id: restored.as_ref().map_or(&p.record.id, |r| &r.id).clone(),
+            old: (action != Action::Create).then(|| p.record.content.clone()),
+            new: restored.map(|restored| restored.content),
+            status: match result.as_ref() {
+                Ok(status) => Some(*status),
+                Err(e) => crate::cloudflare::error_status(e),
+            },
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        if let Err(e) = result {
+            warn!(
+                "Unable to roll back the change to {}: {:#}",
+                &p.record.name, e
+            );
+            // The record is still in place, so it keeps its owner
+            p.claimed = false;
+        }
+    }
+}
+
+/// Records the outcome of a change to `record` in the audit log
+fn audit(action: Action, change: &Change, record: &DnsRecord, result: &anyhow::Result<u16>) {
+    audit::record(&AuditEntry {
+        timestamp: Timestamp::now(),
+        action,
+        interface: change.interface.clone(),
+        zone: change.zone.clone(),
+        record: change.record.clone(),
+        ty: change.ty,
+        id: record.id.clone(),
+        old: change.old.clone(),
+        new: (action != Action::Delete).then(|| change.new.clone()),
+        status: match result {
+            Ok(status) => Some(*status),
+            Err(e) => crate::cloudflare::error_status(e),
+        },
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+    });
+}
+
+/// Replaces the status of the record described by `status`, or adds it if not yet present
+///
+/// The time of the last change to the record is carried over if `status` doesn't reflect a change.
+fn update_status(statuses: &mut Vec<SyncStatus>, mut status: SyncStatus) {
+    let existing = statuses
+        .iter_mut()
+        .find(|s| s.zone == status.zone && s.name == status.name && s.ty == status.ty);
+    match existing {
+        Some(existing) => {
+            status.changed = status.changed.or(existing.changed);
+            *existing = status;
+        }
+        None => statuses.push(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::cloudflare::tests::{failure, stand_in, success, Request};
+
+    /// Returns a record in the test zone, as Cloudflare would describe it
+    fn upstream(id: &str, name: &str, content: &str) -> Value {
+        json!({
+            "id": id,
+            "zone_id": "zone",
+            "name": name,
+            "type": "A",
+            "content": content,
+        })
+    }
+
+    /// Returns a record as it would be configured, i.e. without an id
+    fn desired(name: &str, content: &str) -> Value {
+        json!({
+            "zone_id": "zone",
+            "name": name,
+            "type": "A",
+            "content": content,
+        })
+    }
+
+    fn record(value: Value) -> DnsRecord {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn zone() -> Zone {
+        serde_json::from_value(json!({ "id": "zone", "name": "example.com" })).unwrap()
+    }
+
+    /// Returns the pending change which syncs `desired`, given the `current` record in Cloudflare
+    fn pending(desired: Value, current: Option<Value>) -> Pending {
+        let desired = record(desired);
+        let current = current.map(record);
+        let operation = Operation::between(&desired, current.as_ref());
+        Pending::new(
+            "wan0",
+            "example.com",
+            desired,
+            current,
+            &operation,
+            RecordHooks::default(),
+        )
+    }
+
+    /// Updates foo.example.com, and creates bar.example.com
+    fn changes() -> Vec<Pending> {
+        vec![
+            pending(
+                desired("foo.example.com", "5.6.7.8"),
+                Some(upstream("a", "foo.example.com", "1.2.3.4")),
+            ),
+            pending(desired("bar.example.com", "5.6.7.8"), None),
+        ]
+    }
+
+    fn requests(received: &std::sync::mpsc::Receiver<Request>) -> Vec<String> {
+        received
+            .try_iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect()
+    }
+
+    #[test]
+    fn commits_changes_as_a_batch() {
+        let (client, received) = stand_in(|_| {
+            success(json!({
+                "posts": [upstream("b", "bar.example.com", "5.6.7.8")],
+                "patches": [upstream("a", "foo.example.com", "5.6.7.8")],
+            }))
+        });
+        let mut pending = changes();
+        commit(&client, &zone(), &mut pending).unwrap();
+
+        assert_eq!(
+            requests(&received),
+            vec!["POST /zones/zone/dns_records/batch"]
+        );
+        let ids = pending
+            .iter()
+            .map(|p| p.record.id.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn falls_back_to_single_changes_if_batches_are_unavailable() {
+        let (client, received) = stand_in(|request| match request.method.as_str() {
+            "POST" if request.path.ends_with("/batch") => (405, json!({})),
+            "PATCH" => success(upstream("a", "foo.example.com", "5.6.7.8")),
+            _ => success(upstream("b", "bar.example.com", "5.6.7.8")),
+        });
+        let mut pending = changes();
+        commit(&client, &zone(), &mut pending).unwrap();
+
+        assert_eq!(
+            requests(&received),
+            vec![
+                "POST /zones/zone/dns_records/batch",
+                "PATCH /zones/zone/dns_records/a",
+                "POST /zones/zone/dns_records",
+            ]
+        );
+        assert_eq!(pending[1].record.id.as_ref().unwrap().to_string(), "b");
+    }
+
+    #[test]
+    fn rolls_back_single_changes_after_a_failure() {
+        let (client, received) = stand_in(|request| match request.method.as_str() {
+            "POST" if request.path.ends_with("/batch") => (405, json!({})),
+            "PATCH" => success(upstream("a", "foo.example.com", "5.6.7.8")),
+            _ => failure(400, 1004),
+        });
+        let mut pending = changes();
+        assert!(commit(&client, &zone(), &mut pending).is_err());
+
+        let received = received.try_iter().collect::<Vec<_>>();
+        let paths = received
+            .iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "POST /zones/zone/dns_records/batch",
+                "PATCH /zones/zone/dns_records/a",
+                "POST /zones/zone/dns_records",
+                "PATCH /zones/zone/dns_records/a",
+            ]
+        );
+        // The update is reverted, leaving the comment and tags alone, since it didn't change them
+        let rollback = &received[3].body;
+        assert_eq!(rollback["content"], "1.2.3.4");
+        assert!(rollback.get("comment").is_none());
+        assert!(rollback.get("tags").is_none());
+    }
+
+    #[test]
+    fn rolls_back_the_comment_and_tags_if_they_were_changed() {
+        let (client, received) = stand_in(|request| match request.method.as_str() {
+            "POST" if request.path.ends_with("/batch") => (405, json!({})),
+            "PATCH" => success(upstream("a", "foo.example.com", "5.6.7.8")),
+            _ => failure(400, 1004),
+        });
+        let mut desired = desired("foo.example.com", "5.6.7.8");
+        desired["comment"] = json!("managed by cfdns");
+        let mut current = upstream("a", "foo.example.com", "1.2.3.4");
+        current["tags"] = json!(["owner:me"]);
+        let mut pending = changes();
+        pending[0] = self::pending(desired, Some(current));
+        assert!(commit(&client, &zone(), &mut pending).is_err());
+
+        let rollback = received.try_iter().last().unwrap().body;
+        assert_eq!(rollback["content"], "1.2.3.4");
+        // The record had no comment, so it's cleared, and the tags weren't changed, so are left alone
+        assert_eq!(rollback["comment"], "");
+        assert!(rollback.get("tags").is_none());
+    }
 }